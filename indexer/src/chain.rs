@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use alloy_sol_types::SolEvent;
 use hyperware_process_lib::eth::Filter;
-use hyperware_process_lib::logging::{debug, info};
+use hyperware_process_lib::logging::debug;
 use hyperware_process_lib::sqlite::Sqlite;
 use hyperware_process_lib::{eth, hypermap, print_to_terminal, println, timer};
 
@@ -20,7 +20,7 @@ pub fn make_filters(state: &State) -> (eth::Filter, eth::Filter) {
     // );
     let address = state.hypermap.address().to_owned();
     let mint_filter = eth::Filter::new()
-        .address(address.clone())
+        .address(address)
         .from_block(state.last_checkpoint_block)
         .to_block(eth::BlockNumberOrTag::Latest)
         .event(hypermap::contract::Mint::SIGNATURE);
@@ -38,11 +38,12 @@ pub fn make_filters(state: &State) -> (eth::Filter, eth::Filter) {
             keccak256("~instructions"),
             keccak256("~wallet"),
             keccak256("~price"),
+            keccak256("~schema"),
         ]);
     (mint_filter, notes_filter)
 }
 pub fn start_fetch(state: &mut State, db: &Sqlite) -> PendingLogs {
-    let (mints, notes) = make_filters(&state);
+    let (mints, notes) = make_filters(state);
     state
         .hypermap
         .provider
@@ -112,7 +113,7 @@ pub fn handle_log(
             let child_hash = decoded.childhash.to_string();
             let label = String::from_utf8(decoded.label.to_vec())?;
 
-            add_mint(state, db, &parent_hash, child_hash, label)
+            add_mint(state, db, &parent_hash, child_hash, label, log.block_number)
         }
         hypermap::contract::Note::SIGNATURE_HASH => {
             let decoded = hypermap::contract::Note::decode_log_data(log.data(), true).unwrap();
@@ -120,7 +121,14 @@ pub fn handle_log(
             let parent_hash = decoded.parenthash.to_string();
             let note_label = String::from_utf8(decoded.label.to_vec())?;
 
-            add_note(state, db, &parent_hash, note_label, decoded.data)
+            add_note(
+                state,
+                db,
+                &parent_hash,
+                note_label,
+                decoded.data,
+                log.block_number,
+            )
         }
         // hypermap::contract::Fact::SIGNATURE_HASH => {
         //     // let decoded = hypermap::contract::Fact::decode_log_data(log.data(), true).unwrap();
@@ -152,6 +160,7 @@ pub fn add_mint(
     parent_hash: &str,
     child_hash: String,
     name: String,
+    block: Option<u64>,
 ) -> anyhow::Result<()> {
     // info!("adding mint\n{}\n{}\n{}", name, parent_hash, child_hash);
     if name == "hpn-testing-beta" {
@@ -165,10 +174,13 @@ pub fn add_mint(
     let parent = parent_hash.to_string();
     if parent == root {
         state.categories.insert(child_hash.clone(), name.clone());
-        dbm::insert_category(db, child_hash.clone(), name.clone())?;
+        dbm::insert_category(db, child_hash.clone(), name.clone(), block)?;
         return Ok(());
     };
     let _db_insert = dbm::insert_provider(db, parent_hash, child_hash.clone(), name.clone());
+    if let Some(block) = block {
+        dbm::bump_category_block(db, parent_hash.to_string(), block)?;
+    }
     if let Some(category) = state.categories.get(parent_hash) {
         let provider = Provider {
             category: category.to_owned(),
@@ -194,6 +206,7 @@ pub fn add_note(
     parent_hash: &str,
     note_label: String,
     data: eth::Bytes,
+    block: Option<u64>,
 ) -> anyhow::Result<()> {
     // info!("adding note\n{}\n{}", note_label, parent_hash);
     // remove the ~
@@ -205,6 +218,9 @@ pub fn add_note(
     let decoded = decode_datakey(&data.to_string())?;
     debug!("adding note\nkey: {} - value:{}", key, decoded);
     dbm::insert_provider_facts(db, key, decoded.clone(), parent_hash.to_string())?;
+    if let Some(block) = block {
+        dbm::bump_category_block_by_provider(db, parent_hash.to_string(), block)?;
+    }
     if let Some(provider) = state.providers.get_mut(parent_hash) {
         let facts = provider.facts.get_mut(&note_label);
         match facts {
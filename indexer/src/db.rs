@@ -10,14 +10,16 @@ use serde_json::Value;
 // use std::collections::HashMap;
 use std::collections::HashMap;
 
-use crate::helpers::make_json_timestamp;
+use crate::helpers::{category_label, make_json_timestamp};
+use crate::structs::{
+    AuditFilter, ListOptions, SortOrder, DEFAULT_AUDIT_LIMIT, HPN_ROOT_NAME, MAX_AUDIT_LIMIT,
+};
 
 pub fn open_db(our: &Address) -> Result<sqlite::Sqlite, Error> {
     let p = our.package_id();
     // TEMP to start from scratch
     // wipe_db(our)?;
-    let db = sqlite::open(p, "hpn-explorer", None);
-    db
+    sqlite::open(p, "hpn-explorer", None)
 }
 pub fn wipe_db(our: &Address) -> anyhow::Result<()> {
     let p = our.package_id();
@@ -32,8 +34,57 @@ pub fn load_db(our: &Address) -> anyhow::Result<sqlite::Sqlite> {
     if !good {
         write_db_schema(&db)?;
     }
+    migrate_db(&db)?;
     Ok(db)
 }
+
+/// columns added after the first schema: (table, column, type)
+const ADDED_COLUMNS: [(&str, &str, &str); 5] = [
+    ("categories", "updated_block", "INTEGER"),
+    ("providers", "schema", "TEXT"),
+    ("providers", "online", "INTEGER"),
+    ("providers", "latency_ms", "INTEGER"),
+    ("providers", "last_seen", "INTEGER"),
+];
+
+/// tables added after the first schema, created where missing
+const ADDED_TABLES: [&str; 2] = [
+    // append only, one row per CallProvider
+    r#"
+        CREATE TABLE IF NOT EXISTS audit(
+          id INTEGER PRIMARY KEY AUTOINCREMENT,
+          ts INTEGER NOT NULL,
+          caller TEXT NOT NULL,
+          provider_id TEXT NOT NULL,
+          provider_name TEXT NOT NULL,
+          args_digest TEXT NOT NULL,
+          status TEXT NOT NULL,
+          http_status INTEGER NOT NULL,
+          latency_ms INTEGER NOT NULL,
+          bytes INTEGER NOT NULL
+        );"#,
+    "CREATE INDEX IF NOT EXISTS idx_audit_provider ON audit (provider_name, ts);",
+];
+
+/// ALTERs columns into databases created before they existed
+pub fn migrate_db(db: &Sqlite) -> anyhow::Result<()> {
+    for statement in ADDED_TABLES {
+        db.write(statement.to_string(), vec![], None)?;
+    }
+    for (table, column, kind) in ADDED_COLUMNS {
+        let s = format!("PRAGMA table_info({});", table);
+        let columns = db.read(s, vec![])?;
+        let exists = columns
+            .iter()
+            .any(|c| c.get("name").and_then(|n| n.as_str()) == Some(column));
+        if !exists {
+            info!("adding column {}.{}", table, column);
+            let s = format!("ALTER TABLE {} ADD COLUMN {} {};", table, column, kind);
+            db.write(s, vec![], None)?;
+        }
+    }
+    Ok(())
+}
 pub fn check_schema(db: &Sqlite) -> bool {
     let required = ["providers"];
     let mut found = required
@@ -69,7 +120,13 @@ pub fn check_schema(db: &Sqlite) -> bool {
 
 pub fn write_db_schema(db: &Sqlite) -> anyhow::Result<()> {
     let tx_id = db.begin_tx()?;
-    let s0 = "CREATE TABLE categories(name TEXT PRIMARY KEY, hash TEXT NOT NULL);".to_string();
+    let s0 = r#"
+        CREATE TABLE categories(
+          name TEXT PRIMARY KEY,
+          hash TEXT NOT NULL,
+          updated_block INTEGER
+        );"#
+    .to_string();
     let s1 = r#"
         CREATE TABLE providers(
           id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -82,8 +139,12 @@ pub fn write_db_schema(db: &Sqlite) -> anyhow::Result<()> {
           wallet TEXT,
           price TEXT,
           instructions TEXT,
+          schema TEXT,
           category TEXT NOT NULL,
           created INTEGER,
+          online INTEGER,
+          latency_ms INTEGER,
+          last_seen INTEGER,
           FOREIGN KEY (category) REFERENCES categories(name)
         );"#
     .to_string();
@@ -95,19 +156,55 @@ pub fn write_db_schema(db: &Sqlite) -> anyhow::Result<()> {
     db.write(s0, vec![], Some(tx_id))?;
     db.write(s1, vec![], Some(tx_id))?;
     db.write(s2, vec![], Some(tx_id))?;
-    return db.commit_tx(tx_id);
+    db.commit_tx(tx_id)
 }
 // reads
 // writes
-pub fn insert_category(db: &Sqlite, child_hash: String, name: String) -> Result<(), Error> {
+pub fn insert_category(
+    db: &Sqlite,
+    child_hash: String,
+    name: String,
+    block: Option<u64>,
+) -> Result<(), Error> {
     let s1 = r#"
-        INSERT OR IGNORE INTO categories(name, hash) 
-        VALUES (?1, ?2);
+        INSERT OR IGNORE INTO categories(name, hash, updated_block) 
+        VALUES (?1, ?2, ?3);
         "#
     .to_string();
     let p1 = vec![
         serde_json::Value::String(name),
         serde_json::Value::String(child_hash),
+        serde_json::Value::from(block),
+    ];
+    db.write(s1, p1, None)
+}
+/// Moves a category's updated_block forward, never back
+pub fn bump_category_block(db: &Sqlite, category_hash: String, block: u64) -> Result<(), Error> {
+    let s1 = r#"
+        UPDATE categories SET updated_block = MAX(COALESCE(updated_block, 0), ?1)
+        WHERE hash = ?2
+        "#
+    .to_string();
+    let p1 = vec![
+        serde_json::Value::from(block),
+        serde_json::Value::String(category_hash),
+    ];
+    db.write(s1, p1, None)
+}
+/// Same as bump_category_block, starting from one of the category's providers
+pub fn bump_category_block_by_provider(
+    db: &Sqlite,
+    provider_hash: String,
+    block: u64,
+) -> Result<(), Error> {
+    let s1 = r#"
+        UPDATE categories SET updated_block = MAX(COALESCE(updated_block, 0), ?1)
+        WHERE name = (SELECT category FROM providers WHERE hash = ?2)
+        "#
+    .to_string();
+    let p1 = vec![
+        serde_json::Value::from(block),
+        serde_json::Value::String(provider_hash),
     ];
     db.write(s1, p1, None)
 }
@@ -119,7 +216,7 @@ pub fn insert_provider(
 ) -> Result<(), Error> {
     // kiprintln!("inserting provider\n{:#?}", provider);
     let category = get_category(db, parent_hash.to_string())?;
-    let category = category.get(0).ok_or(anyhow!("no category"))?;
+    let category = category.first().ok_or(anyhow!("no category"))?;
     let category = category.get("name").ok_or(anyhow!("no category name"))?;
     let category = category.to_owned();
     let s1 = r#"
@@ -157,41 +254,243 @@ pub fn insert_provider_facts(
     ];
     db.write(s1, p1, None)
 }
+pub struct AuditRow {
+    pub ts: u64,
+    pub caller: String,
+    pub provider_id: String,
+    pub provider_name: String,
+    pub args_digest: String,
+    pub status: String,
+    pub http_status: u16,
+    pub latency_ms: u64,
+    pub bytes: u64,
+}
+pub fn insert_audit(db: &Sqlite, row: AuditRow) -> Result<(), Error> {
+    let s1 = r#"
+        INSERT INTO audit(ts, caller, provider_id, provider_name, args_digest, status, http_status, latency_ms, bytes)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9);
+        "#
+    .to_string();
+    let p1 = vec![
+        serde_json::Value::from(row.ts),
+        serde_json::Value::String(row.caller),
+        serde_json::Value::String(row.provider_id),
+        serde_json::Value::String(row.provider_name),
+        serde_json::Value::String(row.args_digest),
+        serde_json::Value::String(row.status),
+        serde_json::Value::from(row.http_status),
+        serde_json::Value::from(row.latency_ms),
+        serde_json::Value::from(row.bytes),
+    ];
+    db.write(s1, p1, None)
+}
+/// What the last probe found. NULL online means never probed;
+/// latency and last_seen keep their old values when the probe failed.
+pub fn set_reachability(
+    db: &Sqlite,
+    hash: String,
+    online: bool,
+    latency_ms: Option<u64>,
+    last_seen: Option<u64>,
+) -> Result<(), Error> {
+    let s1 = r#"
+        UPDATE providers
+        SET online = ?1, latency_ms = COALESCE(?2, latency_ms), last_seen = COALESCE(?3, last_seen)
+        WHERE hash = ?4
+        "#
+    .to_string();
+    let p1 = vec![
+        serde_json::Value::from(online as u8),
+        serde_json::Value::from(latency_ms),
+        serde_json::Value::from(last_seen),
+        serde_json::Value::String(hash),
+    ];
+    db.write(s1, p1, None)
+}
 // reads
 pub fn get_all(db: &Sqlite) -> Result<Vec<HashMap<String, Value>>> {
     let s = "SELECT * FROM providers".to_string();
     let data = db.read(s, vec![])?;
     Ok(data)
 }
+pub fn get_categories(db: &Sqlite) -> Result<Vec<HashMap<String, Value>>> {
+    let s = r#"
+        SELECT categories.name, categories.hash, categories.updated_block,
+        COUNT(providers.id) AS provider_count
+        FROM categories
+        LEFT JOIN providers ON providers.category = categories.name
+        GROUP BY categories.name
+        ORDER BY categories.name
+        "#
+    .to_string();
+    let data = db.read(s, vec![])?;
+    Ok(data)
+}
 pub fn get_category(db: &Sqlite, hash: String) -> Result<Vec<HashMap<String, Value>>> {
     let s = "SELECT * FROM categories WHERE hash = ?1".to_string();
     let h = serde_json::Value::String(hash);
     let data = db.read(s, vec![h])?;
     Ok(data)
 }
+/// A category's providers, by its label or its full name
 pub fn get_by_category(db: &Sqlite, category: String) -> Result<Vec<HashMap<String, Value>>> {
     let s = "SELECT * FROM providers WHERE category= ?1".to_string();
-    let h = serde_json::Value::String(category);
+    let h = Value::String(category_label(&category, HPN_ROOT_NAME).to_string());
     let data = db.read(s, vec![h])?;
     Ok(data)
 }
-pub fn search_provider(db: &Sqlite, query: String) -> Result<Vec<HashMap<String, Value>>> {
-    let param = format!(r#"'%{}%'"#, query);
+pub fn get_provider_by_name(
+    db: &Sqlite,
+    name: String,
+    category: String,
+) -> Result<Option<HashMap<String, Value>>> {
+    let s = "SELECT * FROM providers WHERE name = ?1 AND category = ?2".to_string();
+    let p = vec![Value::String(name), Value::String(category)];
+    let data = db.read(s, p)?;
+    Ok(data.into_iter().next())
+}
+pub fn get_provider_by_hash(db: &Sqlite, hash: String) -> Result<Option<HashMap<String, Value>>> {
+    let s = "SELECT * FROM providers WHERE hash = ?1".to_string();
+    let data = db.read(s, vec![Value::String(hash)])?;
+    Ok(data.into_iter().next())
+}
+pub fn get_by_provider_id(db: &Sqlite, provider_id: String) -> Result<Vec<HashMap<String, Value>>> {
+    let s = "SELECT * FROM providers WHERE provider_id = ?1".to_string();
+    let data = db.read(s, vec![Value::String(provider_id)])?;
+    Ok(data)
+}
+/// Filtered, sorted slice of the providers table.
+/// `sort_column` must come from a fixed list, it's spliced into the SQL;
+/// `options` gives the order and online_only, `limit` and `offset` are already checked.
+pub fn list_providers(
+    db: &Sqlite,
+    category: Option<String>,
+    query: Option<String>,
+    sort_column: &str,
+    options: &ListOptions,
+    limit: u64,
+    offset: u64,
+) -> Result<Vec<HashMap<String, Value>>> {
+    let mut conditions = vec![];
+    let mut params = vec![];
+    if let Some(category) = category {
+        let label = category_label(&category, HPN_ROOT_NAME);
+        params.push(Value::String(label.to_string()));
+        conditions.push(format!("category = ?{}", params.len()));
+    }
+    if let Some(query) = query {
+        params.push(Value::String(format!("%{}%", query)));
+        let i = params.len();
+        conditions.push(format!(
+            r#"(category LIKE ?{0} COLLATE NOCASE
+            OR name LIKE ?{0} COLLATE NOCASE
+            OR provider_name LIKE ?{0} COLLATE NOCASE
+            OR site LIKE ?{0} COLLATE NOCASE
+            OR description LIKE ?{0} COLLATE NOCASE)"#,
+            i
+        ));
+    }
+    if options.online_only {
+        conditions.push("online = 1".to_string());
+    }
+    let filter = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    };
+    let direction = match options.order {
+        SortOrder::Asc => "ASC",
+        SortOrder::Desc => "DESC",
+    };
+    params.push(Value::from(limit));
+    params.push(Value::from(offset));
     let s = format!(
-        r#"
-        SELECT * FROM providers
-        WHERE (category LIKE {0} COLLATE NOCASE)
-        OR (name LIKE {0} COLLATE NOCASE)
-        OR (provider_name LIKE {0} COLLATE NOCASE)
-        OR (site LIKE {0} COLLATE NOCASE)
-        OR (description LIKE {0} COLLATE NOCASE)
-        "#,
-        param
+        "SELECT * FROM providers {} ORDER BY {} {}, id {} LIMIT ?{} OFFSET ?{}",
+        filter,
+        sort_column,
+        direction,
+        direction,
+        params.len() - 1,
+        params.len()
     );
-    let data = db.read(s, vec![])?;
+    let data = db.read(s, params)?;
+    Ok(data)
+}
+pub fn search_provider(db: &Sqlite, query: String) -> Result<Vec<HashMap<String, Value>>> {
+    let param = Value::String(format!("%{}%", query));
+    let s = r#"
+        SELECT * FROM providers
+        WHERE (category LIKE ?1 COLLATE NOCASE)
+        OR (name LIKE ?1 COLLATE NOCASE)
+        OR (provider_name LIKE ?1 COLLATE NOCASE)
+        OR (site LIKE ?1 COLLATE NOCASE)
+        OR (description LIKE ?1 COLLATE NOCASE)
+        "#
+    .to_string();
+    let data = db.read(s, vec![param])?;
     Ok(data)
 }
 
+pub fn get_audit(db: &Sqlite, filter: &AuditFilter) -> Result<Vec<HashMap<String, Value>>> {
+    let mut conditions = vec![];
+    let mut params = vec![];
+    let mut add = |condition: &str, value: Value| {
+        params.push(value);
+        conditions.push(format!("{} ?{}", condition, params.len()));
+    };
+    if let Some(caller) = &filter.caller {
+        add("caller =", Value::from(caller.as_str()));
+    }
+    if let Some(name) = &filter.provider_name {
+        add("provider_name =", Value::from(name.as_str()));
+    }
+    if let Some(id) = &filter.provider_id {
+        add("provider_id =", Value::from(id.as_str()));
+    }
+    if let Some(status) = &filter.status {
+        add("status =", Value::from(status.as_str()));
+    }
+    if let Some(since) = filter.since {
+        add("ts >=", Value::from(since));
+    }
+    if let Some(until) = filter.until {
+        add("ts <=", Value::from(until));
+    }
+    let where_clause = match conditions.is_empty() {
+        true => String::new(),
+        false => format!("WHERE {}", conditions.join(" AND ")),
+    };
+    let limit = filter
+        .limit
+        .unwrap_or(DEFAULT_AUDIT_LIMIT)
+        .clamp(1, MAX_AUDIT_LIMIT);
+    params.push(Value::from(limit));
+    let s = format!(
+        "SELECT * FROM audit {} ORDER BY id DESC LIMIT ?{}",
+        where_clause,
+        params.len()
+    );
+    db.read(s, params)
+}
+/// Calls and successful calls per provider since `since`, from the audit log
+pub fn get_health(db: &Sqlite, since: u64) -> Result<Vec<HashMap<String, Value>>> {
+    let s = r#"
+        SELECT provider_id, provider_name, status, COUNT(*) AS calls
+        FROM audit WHERE ts >= ?1
+        GROUP BY provider_id, provider_name, status
+        "#
+    .to_string();
+    db.read(s, vec![Value::from(since)])
+}
+/// Every node some provider names as its ~provider-id
+pub fn get_provider_ids(db: &Sqlite) -> Result<Vec<String>> {
+    let s = "SELECT DISTINCT provider_id FROM providers WHERE provider_id IS NOT NULL".to_string();
+    let data = db.read(s, vec![])?;
+    Ok(data
+        .iter()
+        .filter_map(|row| row.get("provider_id")?.as_str().map(|s| s.to_string()))
+        .collect())
+}
 // getters
 
 // pub fn get_casts(db: &Sqlite, fid: u64) -> Result<Vec<CastRes>> {
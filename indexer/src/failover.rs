@@ -0,0 +1,279 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use hyperware_process_lib::sqlite::Sqlite;
+use serde_json::Value;
+
+use crate::db as dbm;
+use crate::helpers::{make_timestamp_ms, make_timestamp_secs};
+use crate::structs::*;
+
+// CallCategory picks among a category's providers by how their calls through
+// this indexer went lately (the audit log) and, among equally healthy ones, by price.
+// providers nobody called lately count as healthy, so new ones get a chance.
+// calls that failed because of the caller (bad arguments) don't count either way.
+
+/// how far back calls count towards a provider's health
+const HEALTH_WINDOW_S: u64 = 60 * 60;
+/// how many providers one call tries at most
+pub const MAX_CANDIDATES: usize = 3;
+
+struct Ranked {
+    candidate: Candidate,
+    /// share of recent calls that succeeded, in tenths so close ones tie
+    health: u64,
+    /// cheapest first, unparseable and missing prices last
+    price: f64,
+}
+
+/// The category's callable providers, best first, at most MAX_CANDIDATES
+pub fn candidates(db: &Sqlite, category: &str) -> anyhow::Result<Vec<Candidate>> {
+    let since = make_timestamp_secs().saturating_sub(HEALTH_WINDOW_S);
+    let health = health(&dbm::get_health(db, since)?);
+    Ok(rank(
+        &dbm::get_by_category(db, category.to_string())?,
+        &health,
+    ))
+}
+
+fn rank(
+    rows: &[HashMap<String, Value>],
+    health: &HashMap<(String, String), u64>,
+) -> Vec<Candidate> {
+    let mut ranked = rows
+        .iter()
+        // never probed is fine, known to be down isn't
+        .filter(|row| row.get("online").and_then(|o| o.as_u64()) != Some(0))
+        .filter_map(|row| {
+            let candidate = Candidate {
+                provider_id: row.get("provider_id")?.as_str()?.to_string(),
+                provider_name: row.get("provider_name")?.as_str()?.to_string(),
+            };
+            let key = (
+                candidate.provider_id.clone(),
+                candidate.provider_name.clone(),
+            );
+            let price = match row.get("price") {
+                Some(Value::String(p)) => p.parse::<f64>().unwrap_or(f64::INFINITY),
+                _ => f64::INFINITY,
+            };
+            Some(Ranked {
+                health: health.get(&key).copied().unwrap_or(10),
+                price,
+                candidate,
+            })
+        })
+        .collect::<Vec<_>>();
+    ranked.sort_by(|a, b| {
+        b.health
+            .cmp(&a.health)
+            .then(a.price.partial_cmp(&b.price).unwrap_or(Ordering::Equal))
+    });
+    ranked
+        .into_iter()
+        .take(MAX_CANDIDATES)
+        .map(|r| r.candidate)
+        .collect()
+}
+
+/// (provider_id, provider_name) -> tenths of recent calls that succeeded,
+/// from the audit's per-status call counts
+fn health(rows: &[HashMap<String, Value>]) -> HashMap<(String, String), u64> {
+    let mut tally = HashMap::<(String, String), (u64, u64)>::new();
+    for row in rows {
+        let (Some(id), Some(name), Some(status), Some(calls)) = (
+            row.get("provider_id").and_then(|v| v.as_str()),
+            row.get("provider_name").and_then(|v| v.as_str()),
+            row.get("status").and_then(|v| v.as_str()),
+            row.get("calls").and_then(|v| v.as_u64()),
+        ) else {
+            continue;
+        };
+        if caused_by_caller(status) {
+            continue;
+        }
+        let (ok, all) = tally.entry((id.to_string(), name.to_string())).or_default();
+        if status == "ok" {
+            *ok += calls;
+        }
+        *all += calls;
+    }
+    tally
+        .into_iter()
+        .filter(|(_, (_, all))| *all > 0)
+        .map(|(key, (ok, all))| (key, ok * 10 / all))
+        .collect()
+}
+
+/// audit statuses of calls the caller got wrong, which say nothing about the provider
+fn caused_by_caller(status: &str) -> bool {
+    let codes = [
+        ErrorCode::BadRequest,
+        ErrorCode::Unprocessable,
+        ErrorCode::Unsupported,
+    ];
+    codes.iter().any(|code| format!("{:?}", code) == status)
+}
+
+/// Whether another provider could serve a call this one failed: when the
+/// provider or its upstream couldn't be reached, timed out or is down. Calls
+/// it refused (bad arguments, no access, no credits) would fail anywhere.
+pub fn worth_failing_over(e: &anyhow::Error) -> bool {
+    let Some(e) = e.downcast_ref::<ApiError>() else {
+        return false;
+    };
+    match e.code {
+        ErrorCode::Timeout | ErrorCode::Unavailable => true,
+        // details hold the provider's own error, none when its node was offline
+        ErrorCode::Upstream => match e.details.as_ref().and_then(|d| d["code"].as_str()) {
+            Some(code) => code.starts_with("Upstream"),
+            None => true,
+        },
+        _ => false,
+    }
+}
+
+/// The call to make after `call` failed, None when there's no one left to try
+pub fn next_attempt(call: &PendingCall) -> Option<PendingCall> {
+    let mut failover = call.failover.clone()?;
+    if failover.next.is_empty() {
+        return None;
+    }
+    let candidate = failover.next.remove(0);
+    failover.tried.push(call.provider_name.clone());
+    Some(PendingCall {
+        provider_id: candidate.provider_id,
+        provider_name: candidate.provider_name,
+        ts: make_timestamp_secs(),
+        started_ms: make_timestamp_ms() as u64,
+        failover: Some(failover),
+        ..call.clone()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn row(value: Value) -> HashMap<String, Value> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn provider(name: &str, price: Option<&str>) -> HashMap<String, Value> {
+        row(json!({
+            "provider_id": format!("{}.os", name),
+            "provider_name": name,
+            "price": price,
+        }))
+    }
+
+    fn names(candidates: Vec<Candidate>) -> Vec<String> {
+        candidates.into_iter().map(|c| c.provider_name).collect()
+    }
+
+    #[test]
+    fn unpriced_providers_rank_after_priced_ones() {
+        let rows = vec![
+            provider("free", None),
+            provider("dear", Some("0.5")),
+            provider("cheap", Some("0.01")),
+        ];
+        assert_eq!(
+            names(rank(&rows, &HashMap::new())),
+            vec!["cheap", "dear", "free"]
+        );
+    }
+
+    #[test]
+    fn healthier_providers_rank_first() {
+        let rows = vec![
+            provider("cheap", Some("0.01")),
+            provider("dear", Some("0.5")),
+        ];
+        let health = HashMap::from([(("cheap.os".to_string(), "cheap".to_string()), 3)]);
+        assert_eq!(names(rank(&rows, &health)), vec!["dear", "cheap"]);
+    }
+
+    #[test]
+    fn offline_providers_are_left_out() {
+        let mut down = provider("down", Some("0.01"));
+        down.insert("online".to_string(), json!(0));
+        let rows = vec![down, provider("up", Some("0.5"))];
+        assert_eq!(names(rank(&rows, &HashMap::new())), vec!["up"]);
+    }
+
+    #[test]
+    fn caller_mistakes_dont_count_against_health() {
+        let counts = |status: &str, calls: u64| {
+            row(json!({
+                "provider_id": "p.os",
+                "provider_name": "p",
+                "status": status,
+                "calls": calls,
+            }))
+        };
+        let key = ("p.os".to_string(), "p".to_string());
+        let rows = vec![
+            counts("ok", 4),
+            counts("Unprocessable", 40),
+            counts("BadRequest", 6),
+        ];
+        assert_eq!(health(&rows).get(&key), Some(&10));
+        let rows = vec![
+            counts("ok", 4),
+            counts("Upstream", 4),
+            counts("Unprocessable", 40),
+        ];
+        assert_eq!(health(&rows).get(&key), Some(&5));
+        let rows = vec![counts("Unprocessable", 3)];
+        assert_eq!(health(&rows).get(&key), None);
+    }
+
+    #[test]
+    fn only_unreachable_or_failing_providers_are_failed_over() {
+        let provider_error = |code: ErrorCode, provider_code: &str| {
+            anyhow::Error::from(
+                ApiError::new(code, "provider returned an error")
+                    .with_details(json!({ "error": "x", "code": provider_code })),
+            )
+        };
+        let offline = anyhow::Error::from(ApiError::new(ErrorCode::Upstream, "offline"));
+        assert!(worth_failing_over(&offline));
+        assert!(worth_failing_over(&provider_error(
+            ErrorCode::Upstream,
+            "UpstreamServer"
+        )));
+        assert!(worth_failing_over(&provider_error(
+            ErrorCode::Upstream,
+            "UpstreamDns"
+        )));
+        assert!(worth_failing_over(&provider_error(
+            ErrorCode::Timeout,
+            "UpstreamTimeout"
+        )));
+        assert!(worth_failing_over(&provider_error(
+            ErrorCode::Unavailable,
+            "Unavailable"
+        )));
+
+        assert!(!worth_failing_over(&provider_error(
+            ErrorCode::Upstream,
+            "Unauthorized"
+        )));
+        assert!(!worth_failing_over(&provider_error(
+            ErrorCode::Upstream,
+            "QuotaExceeded"
+        )));
+        assert!(!worth_failing_over(&provider_error(
+            ErrorCode::Unprocessable,
+            "BadRequest"
+        )));
+        assert!(!worth_failing_over(&provider_error(
+            ErrorCode::RateLimited,
+            "RateLimited"
+        )));
+        let bad = anyhow::Error::from(ApiError::new(ErrorCode::BadRequest, "bad"));
+        assert!(!worth_failing_over(&bad));
+    }
+}
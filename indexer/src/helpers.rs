@@ -5,14 +5,10 @@ use std::time::{SystemTime, UNIX_EPOCH};
 /// Returns an error if the hex string is invalid or the decoded bytes aren't valid ASCII printable characters.
 pub fn decode_datakey(hex_string: &str) -> Result<String> {
     // Remove 0x prefix if present
-    let clean_hex = if hex_string.starts_with("0x") {
-        &hex_string[2..]
-    } else {
-        hex_string
-    };
+    let clean_hex = hex_string.strip_prefix("0x").unwrap_or(hex_string);
 
     // Validate hex string length
-    if clean_hex.len() % 2 != 0 {
+    if !clean_hex.len().is_multiple_of(2) {
         return Err(anyhow!("datakey decoding failed: odd number of hex digits"));
     }
 
@@ -30,7 +26,7 @@ pub fn decode_datakey(hex_string: &str) -> Result<String> {
         .map_err(|_| anyhow!("datakey decoding failed: invalid UTF-8 sequence"))?;
 
     // Check if all characters are printable ASCII (range 0x20 to 0x7E)
-    if decoded.chars().all(|c| c >= ' ' && c <= '~') {
+    if decoded.chars().all(|c| (' '..='~').contains(&c)) {
         Ok(decoded)
     } else {
         Err(anyhow!(
@@ -39,6 +35,38 @@ pub fn decode_datakey(hex_string: &str) -> Result<String> {
     }
 }
 
+/// Splits a full provider name like `foo.weather.hpn-testing-beta.os`
+/// into its label and category. The root suffix is optional.
+pub fn split_provider_name(name: &str, root: &str) -> Result<(String, String)> {
+    let name = name.strip_suffix(root).unwrap_or(name);
+    let name = name.trim_end_matches('.');
+    match name.split_once('.') {
+        Some((label, category)) if !label.is_empty() && !category.contains('.') => {
+            Ok((label.to_string(), category.to_string()))
+        }
+        _ => Err(anyhow!("bad provider name: {}", name)),
+    }
+}
+
+/// A category's label, from either the label itself or the category's full
+/// name like `weather.hpn-testing-beta.os`
+pub fn category_label<'a>(category: &'a str, root: &str) -> &'a str {
+    category
+        .strip_suffix(root)
+        .and_then(|c| c.strip_suffix('.'))
+        .unwrap_or(category)
+}
+
+/// Lowercases a namehash and makes sure it has its 0x prefix
+pub fn normalize_hash(hash: &str) -> String {
+    let hash = hash.to_lowercase();
+    if hash.starts_with("0x") {
+        hash
+    } else {
+        format!("0x{}", hash)
+    }
+}
+
 pub fn make_json_timestamp() -> serde_json::Number {
     let systemtime = SystemTime::now();
 
@@ -46,8 +74,7 @@ pub fn make_json_timestamp() -> serde_json::Number {
         .duration_since(UNIX_EPOCH)
         .expect("Time went backwards");
     let secs = duration_since_epoch.as_secs();
-    let now: serde_json::Number = secs.into();
-    return now;
+    secs.into()
 }
 pub fn make_timestamp_secs() -> u64 {
     let systemtime = SystemTime::now();
@@ -55,8 +82,7 @@ pub fn make_timestamp_secs() -> u64 {
     let duration_since_epoch = systemtime
         .duration_since(UNIX_EPOCH)
         .expect("Time went backwards");
-    let secs = duration_since_epoch.as_secs();
-    return secs;
+    duration_since_epoch.as_secs()
 }
 pub fn make_timestamp_ms() -> u128 {
     let systemtime = SystemTime::now();
@@ -64,6 +90,39 @@ pub fn make_timestamp_ms() -> u128 {
     let duration_since_epoch = systemtime
         .duration_since(UNIX_EPOCH)
         .expect("Time went backwards");
-    let ms = duration_since_epoch.as_millis();
-    return ms;
+    duration_since_epoch.as_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn provider_names_split_into_label_and_category() {
+        let root = "hpn-testing-beta.os";
+        let split = |name: &str| split_provider_name(name, root).ok();
+        let expected = Some(("foo".to_string(), "weather".to_string()));
+        assert_eq!(split("foo.weather.hpn-testing-beta.os"), expected);
+        assert_eq!(split("foo.weather"), expected);
+        assert_eq!(split("foo.weather."), expected);
+        // a category alone, an empty label or a deeper name aren't provider names
+        assert_eq!(split("weather.hpn-testing-beta.os"), None);
+        assert_eq!(split(".weather"), None);
+        assert_eq!(split("foo.bar.weather"), None);
+        assert_eq!(split("foo.weather.other-root.os"), None);
+    }
+
+    #[test]
+    fn categories_are_found_by_label_or_full_name() {
+        let root = "hpn-testing-beta.os";
+        assert_eq!(category_label("weather", root), "weather");
+        assert_eq!(
+            category_label("weather.hpn-testing-beta.os", root),
+            "weather"
+        );
+        assert_eq!(
+            category_label("weatherhpn-testing-beta.os", root),
+            "weatherhpn-testing-beta.os"
+        );
+    }
 }
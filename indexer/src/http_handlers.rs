@@ -1,12 +1,19 @@
 use std::collections::HashMap;
 
-use crate::{db as dbm, structs::*};
+use crate::audit_common::arguments_digest;
+use crate::helpers::{make_timestamp_ms, make_timestamp_secs, normalize_hash, split_provider_name};
+use crate::{db as dbm, failover, structs::*};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use hyperware_process_lib::http::server::{send_response, HttpServerRequest};
 use hyperware_process_lib::http::{Method, StatusCode};
 use hyperware_process_lib::logging::info;
 use hyperware_process_lib::sqlite::Sqlite;
-use hyperware_process_lib::{last_blob, Address, Request, Response};
-use serde_json::json;
+use hyperware_process_lib::{last_blob, Address, LazyLoadBlob, Request, Response, SendErrorKind};
+use serde_json::{json, Value};
+
+/// how long CallProvider waits on the provider process
+const PROVIDER_TIMEOUT_S: u64 = 60;
 
 pub fn handle_frontend(
     our: &Address,
@@ -15,50 +22,47 @@ pub fn handle_frontend(
     db: &Sqlite,
 ) -> anyhow::Result<()> {
     let server_request: HttpServerRequest = serde_json::from_slice(body)?;
-    match server_request {
-        HttpServerRequest::Http(req) => {
-            let prefix = format!("{}:{}/api", our.process(), our.package_id());
-            let path = req.bound_path(Some(&prefix));
-            info!("request path: {}", path);
-            let met = req.method()?;
-            match met {
-                Method::GET => {
-                    match handle_get(our, path, req.query_params(), state, db) {
-                        Ok(_) => (),
-                        Err(e) => {
-                            info!("error handling get request\n{:#?}", e);
-                            send_response(StatusCode::INTERNAL_SERVER_ERROR, None, vec![]);
-                        }
-                    };
-                }
-                Method::POST => match handle_post(db) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        info!("error handling post request\n{:#?}", e);
-                        send_response(StatusCode::SERVICE_UNAVAILABLE, None, vec![]);
-                    }
-                },
-                _ => {
-                    send_response(StatusCode::METHOD_NOT_ALLOWED, None, vec![]);
-                }
+    if let HttpServerRequest::Http(req) = server_request {
+        let prefix = format!("{}:{}/api", our.process(), our.package_id());
+        let path = req.bound_path(Some(&prefix));
+        info!("request path: {}", path);
+        let met = req.method()?;
+        let res = match met {
+            Method::GET => handle_get(our, path, req.query_params(), req.url_params(), state, db),
+            Method::POST => {
+                // /api/mcp is open, so the client's address is all we know of the caller
+                let caller = req
+                    .source_socket_addr()
+                    .map(|a| a.ip().to_string())
+                    .unwrap_or("unknown".to_string());
+                handle_post(db, &state.audit_key, &caller)
             }
+            _ => Err(ApiError::new(
+                ErrorCode::MethodNotAllowed,
+                format!("method not allowed: {}", met),
+            )
+            .into()),
+        };
+        if let Err(e) = res {
+            info!("error handling {} request\n{:#?}", met, e);
+            send_error_response(&ApiError::from(e))?;
         }
-        _ => (),
-    };
+    }
     Ok(())
 }
-fn handle_post(db: &Sqlite) -> anyhow::Result<()> {
-    let blob = last_blob().ok_or(anyhow::anyhow!("no blob"))?;
+fn handle_post(db: &Sqlite, audit_key: &str, caller: &str) -> anyhow::Result<()> {
+    let blob = last_blob().ok_or(ApiError::new(ErrorCode::BadRequest, "no body"))?;
     // let json = std::str::from_utf8(blob.bytes());
     // kiprintln!("json\n:{:#?}", json);
-    let body = serde_json::from_slice::<HttpPostRequest>(blob.bytes())?;
-    handle_mcp(db, body)?;
+    let body = parse_request::<HttpPostRequest>(blob.bytes())?;
+    handle_mcp(db, audit_key, caller, body)?;
     Ok(())
 }
 fn handle_get(
     _our: &Address,
     path: &str,
     params: &HashMap<String, String>,
+    url_params: &HashMap<String, String>,
     state: &mut State,
     db: &Sqlite,
 ) -> anyhow::Result<()> {
@@ -75,27 +79,241 @@ fn handle_get(
             send_json_response(StatusCode::OK, &json!(state.providers))?;
         }
         "/all" => {
-            let data = dbm::get_all(db)?;
-            send_json_response(StatusCode::OK, &json!(data))?;
+            send_listing(db, None, None, params)?;
         }
         "/cat" => {
-            let query = params.get("cat").ok_or(anyhow::anyhow!("no category"))?;
-            let data = dbm::get_by_category(db, query.to_string())?;
-            send_json_response(StatusCode::OK, &json!(data))?;
+            let query = params.get("cat").ok_or(missing_param("cat"))?;
+            send_listing(db, Some(query.to_string()), None, params)?;
         }
         "/search" => {
-            let query = params.get("q").ok_or(anyhow::anyhow!("no query"))?;
-            let data = dbm::search_provider(db, query.to_string())?;
-            send_json_response(StatusCode::OK, &json!(data))?;
+            let query = params.get("q").ok_or(missing_param("q"))?;
+            send_listing(db, None, Some(query.to_string()), params)?;
+        }
+        "/categories" => {
+            let data = list_categories(db)?;
+            send_json_response(StatusCode::OK, &data)?;
+        }
+        "/audit" => {
+            let filter = audit_filter_from_query(params)?;
+            let data = dbm::get_audit(db, &filter)?;
+            send_json_response(StatusCode::OK, &data)?;
+        }
+        "/provider/name/:name" | "/provider/hash/:hash" | "/provider/id/:id" => {
+            let lookup = if let Some(name) = url_params.get("name") {
+                ProviderLookup::Name(name.to_string())
+            } else if let Some(hash) = url_params.get("hash") {
+                ProviderLookup::Hash(hash.to_string())
+            } else {
+                let id = url_params.get("id").ok_or(missing_param("id"))?;
+                ProviderLookup::ProviderId(id.to_string())
+            };
+            let data = lookup_provider(db, lookup)?
+                .ok_or(ApiError::new(ErrorCode::NotFound, "no such provider"))?;
+            send_json_response(StatusCode::OK, &data)?;
         }
         _ => {
-            send_json_response(StatusCode::NOT_FOUND, &json!(false))?;
+            let message = format!("no such path: {}", path);
+            return Err(ApiError::new(ErrorCode::NotFound, message).into());
         }
     };
     Ok(())
 }
 
-fn handle_mcp(db: &Sqlite, req: HttpPostRequest) -> anyhow::Result<()> {
+/// Reads `limit`, `cursor`, `sort` (created|name|price), `order` (asc|desc),
+/// a comma separated `fields` list and `online_only` (true|false) from the query string
+fn list_options_from_query(params: &HashMap<String, String>) -> anyhow::Result<ListOptions> {
+    let bad = |m: String| ApiError::new(ErrorCode::BadRequest, m);
+    let limit = match params.get("limit") {
+        None => None,
+        Some(l) => Some(
+            l.parse::<u64>()
+                .map_err(|_| bad(format!("bad limit: {}", l)))?,
+        ),
+    };
+    let sort = match params.get("sort").map(|s| s.as_str()) {
+        None => None,
+        Some("created") => Some(SortKey::Created),
+        Some("name") => Some(SortKey::Name),
+        Some("price") => Some(SortKey::Price),
+        Some(other) => return Err(bad(format!("bad sort key: {}", other)).into()),
+    };
+    let order = match params.get("order").map(|s| s.as_str()) {
+        None | Some("asc") => SortOrder::Asc,
+        Some("desc") => SortOrder::Desc,
+        Some(other) => return Err(bad(format!("bad order: {}", other)).into()),
+    };
+    let fields = params
+        .get("fields")
+        .map(|f| f.split(',').map(|s| s.trim().to_string()).collect());
+    let online_only = match params.get("online_only").map(|s| s.as_str()) {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(other) => return Err(bad(format!("bad online_only: {}", other)).into()),
+    };
+    Ok(ListOptions {
+        limit,
+        cursor: params.get("cursor").cloned(),
+        sort,
+        order,
+        fields,
+        online_only,
+    })
+}
+
+/// Answers a listing route. Asking for a `limit` or a `cursor` gets a Page;
+/// without either, every row comes back as a plain array, as before paging.
+fn send_listing(
+    db: &Sqlite,
+    category: Option<String>,
+    query: Option<String>,
+    params: &HashMap<String, String>,
+) -> anyhow::Result<()> {
+    let mut options = list_options_from_query(params)?;
+    if options.limit.is_some() || options.cursor.is_some() {
+        let page = list_page(db, category, query, options)?;
+        return send_json_response(StatusCode::OK, &page);
+    }
+    options.limit = Some(MAX_PAGE_LIMIT);
+    let mut items = vec![];
+    loop {
+        let page = list_page(db, category.clone(), query.clone(), options.clone())?;
+        items.extend(page.items);
+        match page.next_cursor {
+            Some(cursor) => options.cursor = Some(cursor),
+            None => break,
+        }
+    }
+    send_json_response(StatusCode::OK, &items)
+}
+
+/// One page of providers. The cursor is the row offset of the next page.
+fn list_page(
+    db: &Sqlite,
+    category: Option<String>,
+    query: Option<String>,
+    options: ListOptions,
+) -> anyhow::Result<Page> {
+    let bad = |m: String| ApiError::new(ErrorCode::BadRequest, m);
+    let limit = options
+        .limit
+        .unwrap_or(DEFAULT_PAGE_LIMIT)
+        .clamp(1, MAX_PAGE_LIMIT);
+    let offset = match &options.cursor {
+        None => 0,
+        Some(c) => c
+            .parse::<u64>()
+            .map_err(|_| bad(format!("bad cursor: {}", c)))?,
+    };
+    if let Some(fields) = &options.fields {
+        if let Some(f) = fields
+            .iter()
+            .find(|f| !PROVIDER_COLUMNS.contains(&f.as_str()))
+        {
+            return Err(bad(format!("unknown field: {}", f)).into());
+        }
+    }
+    let sort_column = match options.sort {
+        None => "id",
+        Some(SortKey::Created) => "created",
+        Some(SortKey::Name) => "name",
+        Some(SortKey::Price) => "CAST(price AS REAL)",
+    };
+    // fetch one extra row to know whether there is a next page
+    let mut rows = dbm::list_providers(
+        db,
+        category,
+        query,
+        sort_column,
+        &options,
+        limit + 1,
+        offset,
+    )?;
+    let next_cursor = if rows.len() as u64 > limit {
+        rows.truncate(limit as usize);
+        Some((offset + limit).to_string())
+    } else {
+        None
+    };
+    let items = rows
+        .into_iter()
+        .map(|mut row| {
+            if let Some(fields) = &options.fields {
+                row.retain(|k, _| fields.contains(k));
+            }
+            json!(row)
+        })
+        .collect();
+    Ok(Page { items, next_cursor })
+}
+
+/// Reads `caller`, `provider_name`, `provider_id`, `status` (ok or an error code),
+/// `since` and `until` (unix seconds) and `limit`
+pub fn audit_filter_from_query(params: &HashMap<String, String>) -> anyhow::Result<AuditFilter> {
+    let number = |key: &str| -> Result<Option<u64>, ApiError> {
+        match params.get(key) {
+            None => Ok(None),
+            Some(v) => v
+                .parse::<u64>()
+                .map(Some)
+                .map_err(|_| ApiError::new(ErrorCode::BadRequest, format!("bad {}: {}", key, v))),
+        }
+    };
+    Ok(AuditFilter {
+        caller: params.get("caller").cloned(),
+        provider_name: params.get("provider_name").cloned(),
+        provider_id: params.get("provider_id").cloned(),
+        status: params.get("status").cloned(),
+        since: number("since")?,
+        until: number("until")?,
+        limit: number("limit")?,
+    })
+}
+
+fn list_categories(db: &Sqlite) -> anyhow::Result<Vec<Category>> {
+    let rows = dbm::get_categories(db)?;
+    let categories = rows
+        .into_iter()
+        .filter_map(|row| {
+            let name = row.get("name")?.as_str()?.to_string();
+            let hash = row.get("hash")?.as_str()?.to_string();
+            Some(Category {
+                full_name: format!("{}.{}", name, HPN_ROOT_NAME),
+                name,
+                hash,
+                provider_count: row.get("provider_count")?.as_u64().unwrap_or(0),
+                updated_block: row.get("updated_block").and_then(|b| b.as_u64()),
+            })
+        })
+        .collect();
+    Ok(categories)
+}
+
+/// Exact provider lookup. Name and hash give a single provider,
+/// provider id gives every provider hosted on that node.
+fn lookup_provider(db: &Sqlite, lookup: ProviderLookup) -> anyhow::Result<Option<Value>> {
+    let data = match lookup {
+        ProviderLookup::Name(name) => {
+            let (label, category) = split_provider_name(&name, HPN_ROOT_NAME)
+                .map_err(|e| ApiError::new(ErrorCode::BadRequest, e))?;
+            dbm::get_provider_by_name(db, label, category)?.map(|p| json!(p))
+        }
+        ProviderLookup::Hash(hash) => {
+            dbm::get_provider_by_hash(db, normalize_hash(&hash))?.map(|p| json!(p))
+        }
+        ProviderLookup::ProviderId(node) => {
+            let data = dbm::get_by_provider_id(db, node)?;
+            (!data.is_empty()).then(|| json!(data))
+        }
+    };
+    Ok(data)
+}
+
+fn handle_mcp(
+    db: &Sqlite,
+    audit_key: &str,
+    caller: &str,
+    req: HttpPostRequest,
+) -> anyhow::Result<()> {
     info!("mcp request\n{:#?}", req);
     match req {
         HttpPostRequest::SearchRegistry(query) => {
@@ -106,44 +324,371 @@ fn handle_mcp(db: &Sqlite, req: HttpPostRequest) -> anyhow::Result<()> {
             provider_id,
             provider_name,
             arguments,
+            api_key,
+            format,
         } => {
-            let jsonbody = json!({"MCP": {"provider_name": provider_name, "arguments": arguments}});
-            info!("{:#?}", jsonbody);
-            let process = ("provider", "hpn", "sortugdev.os");
-            let target = Address::new(provider_id, process);
-            let res = Request::new()
-                .target(target)
-                .body(serde_json::to_vec(&jsonbody)?)
-                .send_and_await_response(60)??;
-            let resbody = res.body().to_vec();
+            let call = PendingCall {
+                provider_id,
+                provider_name,
+                format,
+                caller: caller.to_string(),
+                args_digest: arguments_digest(audit_key, &arguments),
+                ts: make_timestamp_secs(),
+                started_ms: make_timestamp_ms() as u64,
+                failover: None,
+            };
+            send_call_provider(&call, &arguments, api_key.as_deref())?;
+        }
+        HttpPostRequest::CallCategory {
+            category,
+            arguments,
+            api_key,
+            format,
+        } => {
+            let mut candidates = failover::candidates(db, &category)?.into_iter();
+            let first = candidates.next().ok_or(ApiError::new(
+                ErrorCode::NotFound,
+                format!("no callable providers in {}", category),
+            ))?;
+            let call = PendingCall {
+                provider_id: first.provider_id,
+                provider_name: first.provider_name,
+                format,
+                caller: caller.to_string(),
+                args_digest: arguments_digest(audit_key, &arguments),
+                ts: make_timestamp_secs(),
+                started_ms: make_timestamp_ms() as u64,
+                failover: Some(Failover {
+                    category,
+                    arguments: arguments.clone(),
+                    api_key: api_key.clone(),
+                    next: candidates.collect(),
+                    tried: vec![],
+                }),
+            };
+            send_call_provider(&call, &arguments, api_key.as_deref())?;
+        }
+        HttpPostRequest::ListTools { category, query } => {
+            let rows = match (category, query) {
+                (Some(category), _) => dbm::get_by_category(db, category)?,
+                (None, Some(query)) => dbm::search_provider(db, query)?,
+                (None, None) => dbm::get_all(db)?,
+            };
+            let tools = rows.iter().map(provider_tool).collect::<Vec<_>>();
+            send_json_response(StatusCode::OK, &json!({ "tools": tools }))?;
+        }
+    };
+    Ok(())
+}
+/// Sends the call to the provider's node without waiting on it. The HTTP request
+/// stays open until handle_provider_response or handle_provider_send_error answer it.
+fn send_call_provider(
+    call: &PendingCall,
+    arguments: &HashMap<String, Value>,
+    api_key: Option<&str>,
+) -> anyhow::Result<()> {
+    let jsonbody = json!({"MCP": {
+        "provider_name": call.provider_name,
+        "arguments": arguments,
+        "api_key": api_key,
+        // leave time for the answer to travel back before we stop waiting
+        "deadline_ms": (PROVIDER_TIMEOUT_S - 5) * 1000,
+    }});
+    info!("{:#?}", jsonbody);
+    let process = ("provider", "hpn", "sortugdev.os");
+    let target = Address::new(call.provider_id.clone(), process);
+    Request::new()
+        .target(target)
+        .body(serde_json::to_vec(&jsonbody)?)
+        .expects_response(PROVIDER_TIMEOUT_S)
+        .context(serde_json::to_vec(&ProviderContext::Call(Box::new(
+            call.clone(),
+        )))?)
+        .send()?;
+    Ok(())
+}
+/// The provider answered a CallProvider
+pub fn handle_provider_response(db: &Sqlite, body: &[u8], call: PendingCall) -> anyhow::Result<()> {
+    // failover calls say who answered, the caller didn't pick
+    let served_by = call.failover.as_ref().map(|_| call.provider_name.as_str());
+    let res = answer_call_provider(
+        &call.provider_name,
+        body,
+        last_blob(),
+        &call.format,
+        served_by,
+    );
+    finish_call(db, &call, res)
+}
+/// A CallProvider never reached the provider or got no answer in time
+pub fn handle_provider_send_error(
+    db: &Sqlite,
+    kind: &SendErrorKind,
+    call: PendingCall,
+) -> anyhow::Result<()> {
+    let e = match kind {
+        SendErrorKind::Timeout => ApiError::new(ErrorCode::Timeout, "provider timed out"),
+        SendErrorKind::Offline => ApiError::new(ErrorCode::Upstream, "provider node is offline"),
+    };
+    finish_call(db, &call, Err(e.into()))
+}
+/// Audits the call and, if it failed, moves on to the next candidate of a
+/// CallCategory or answers the client with the error
+fn finish_call(db: &Sqlite, call: &PendingCall, res: anyhow::Result<usize>) -> anyhow::Result<()> {
+    let latency_ms = (make_timestamp_ms() as u64).saturating_sub(call.started_ms);
+    audit_call(db, call, &res, latency_ms);
+    let Err(e) = res else {
+        return Ok(());
+    };
+    info!("call to {} failed\n{:#?}", call.provider_name, e);
+    if let Some(next) = failover::worth_failing_over(&e)
+        .then(|| failover::next_attempt(call))
+        .flatten()
+    {
+        info!("trying {} instead", next.provider_name);
+        if let Some(f) = &next.failover {
+            return send_call_provider(&next, &f.arguments, f.api_key.as_deref());
+        }
+    }
+    let e = ApiError::from(e);
+    let e = match &call.failover {
+        Some(f) => {
+            let mut tried = f.tried.clone();
+            tried.push(call.provider_name.clone());
+            ApiError::new(
+                e.code.clone(),
+                format!("no provider in {} could serve the call", f.category),
+            )
+            .with_details(json!({ "tried": tried, "last_error": e }))
+        }
+        None => e,
+    };
+    send_error_response(&e)
+}
+/// Appends the call's audit row; a failing write is logged, never fails the call
+fn audit_call(db: &Sqlite, call: &PendingCall, res: &anyhow::Result<usize>, latency_ms: u64) {
+    let mut row = dbm::AuditRow {
+        ts: call.ts,
+        caller: call.caller.clone(),
+        provider_id: call.provider_id.clone(),
+        provider_name: call.provider_name.clone(),
+        args_digest: call.args_digest.clone(),
+        status: "ok".to_string(),
+        http_status: StatusCode::OK.as_u16(),
+        latency_ms,
+        bytes: 0,
+    };
+    match res {
+        Ok(bytes) => row.bytes = *bytes as u64,
+        Err(e) => {
+            let code = e
+                .downcast_ref::<ApiError>()
+                .map(|e| e.code.clone())
+                .unwrap_or(ErrorCode::Internal);
+            row.status = format!("{:?}", code);
+            row.http_status = status_for(&code).as_u16();
+        }
+    }
+    if let Err(e) = dbm::insert_audit(db, row) {
+        info!("audit write failed: {:?}", e);
+    }
+}
+/// Turns the provider's answer into the HTTP response. The provider answers
+/// {"status", "headers", "mime", "body", "latency_ms"}, with binary and large
+/// bodies in the blob, or {"error", "code", "details"} when it or the upstream failed.
+/// `served_by` goes in an X-Served-By header and a "served_by" field of JSON answers.
+/// Returns the size of the provider's response.
+fn answer_call_provider(
+    provider_name: &str,
+    body: &[u8],
+    blob: Option<LazyLoadBlob>,
+    format: &ResponseFormat,
+    served_by: Option<&str>,
+) -> anyhow::Result<usize> {
+    let envelope = serde_json::from_slice::<Value>(body).unwrap_or_default();
+    if envelope.get("error").is_some() {
+        let e = match envelope["code"].as_str() {
+            // arguments that failed the provider's schema are the caller's fault
+            Some("BadRequest") => {
+                ApiError::new(ErrorCode::Unprocessable, "arguments rejected by provider")
+            }
+            Some("UpstreamTimeout") => ApiError::new(ErrorCode::Timeout, "upstream timed out"),
+            Some("RateLimited") => {
+                ApiError::new(ErrorCode::RateLimited, "provider is rate limiting")
+            }
+            Some("Unavailable") => {
+                ApiError::new(ErrorCode::Unavailable, "provider's upstream is down")
+            }
+            _ => ApiError::new(ErrorCode::Upstream, "provider returned an error"),
+        };
+        return Err(e.with_details(envelope).into());
+    }
+    let mime = blob
+        .as_ref()
+        .and_then(|b| b.mime.clone())
+        .or(envelope["mime"].as_str().map(|m| m.to_string()))
+        .unwrap_or("application/octet-stream".to_string());
+    let bytes = blob.as_ref().map(|b| b.bytes.len()).unwrap_or(body.len());
+    let headers = |c: &str| {
+        let mut headers = HashMap::from([("Content-Type".to_string(), c.to_string())]);
+        if let Some(name) = served_by {
+            headers.insert("X-Served-By".to_string(), name.to_string());
+        }
+        Some(headers)
+    };
+    let with_served_by = |mut v: Value| {
+        if let (Some(name), Some(obj)) = (served_by, v.as_object_mut()) {
+            obj.insert("served_by".to_string(), json!(name));
+        }
+        v
+    };
+    match (format, blob) {
+        (ResponseFormat::Raw, Some(blob)) => {
+            send_response(StatusCode::OK, headers(&mime), blob.bytes);
+            Ok(bytes)
+        }
+        (ResponseFormat::Raw, None) => {
+            let body = match served_by {
+                Some(_) => serde_json::to_vec(&with_served_by(envelope))?,
+                None => body.to_vec(),
+            };
+            send_response(StatusCode::OK, headers("application/json"), body);
+            Ok(bytes)
+        }
+        (ResponseFormat::Mcp, blob) => {
+            let block = match blob {
+                Some(blob) if mime.starts_with("image/") => json!({
+                    "type": "image",
+                    "data": BASE64.encode(&blob.bytes),
+                    "mimeType": mime,
+                }),
+                Some(blob) => json!({
+                    "type": "resource",
+                    "resource": {
+                        "uri": format!("hpn://{}", provider_name),
+                        "mimeType": mime,
+                        "blob": BASE64.encode(&blob.bytes),
+                    },
+                }),
+                None => {
+                    let text = match &envelope["body"] {
+                        Value::String(s) => s.clone(),
+                        other => other.to_string(),
+                    };
+                    json!({ "type": "text", "text": text })
+                }
+            };
+            let result = with_served_by(json!({ "content": [block], "isError": false }));
             send_response(
                 StatusCode::OK,
-                Some(HashMap::from([(
-                    String::from("Content-Type"),
-                    String::from("application/json"),
-                )])),
-                resbody,
+                headers("application/json"),
+                serde_json::to_vec(&result)?,
             );
+            Ok(bytes)
+        }
+    }
+}
+/// An MCP tool description for a provider row. Providers that haven't
+/// published a ~schema (or published one that isn't JSON) take any object.
+fn provider_tool(row: &HashMap<String, Value>) -> Value {
+    let input_schema = row
+        .get("schema")
+        .and_then(|s| s.as_str())
+        .and_then(|s| serde_json::from_str::<Value>(s).ok())
+        .filter(|s| s.is_object())
+        .unwrap_or(json!({"type": "object"}));
+    json!({
+        "name": row.get("name"),
+        "description": row.get("description"),
+        "inputSchema": input_schema,
+        "providerId": row.get("provider_id"),
+        "providerName": row.get("provider_name"),
+    })
+}
+pub fn handle_client_request(body: &[u8], db: &Sqlite) -> anyhow::Result<()> {
+    let res: ClientResponse = parse_request::<ClientRequest>(body).and_then(|req| {
+        info!("{:#?}", req);
+        route_client_request(req, db)
+    });
+    if let Err(e) = &res {
+        info!("client request failed\n{:#?}", e);
+    }
+    send_hyperware_response(&res)
+}
+/// Tells apart broken JSON, unknown request variants and bad shapes.
+/// The variant is read off the JSON first, `"Variant"` or `{"Variant": ...}`,
+/// and checked against T's own variant names.
+fn parse_request<T: serde::de::DeserializeOwned>(body: &[u8]) -> Result<T, ApiError> {
+    let value = serde_json::from_slice::<Value>(body)
+        .map_err(|e| ApiError::new(ErrorCode::BadRequest, format!("invalid JSON: {}", e)))?;
+    let variant = match &value {
+        Value::String(variant) => variant.as_str(),
+        Value::Object(map) if map.len() == 1 => map.keys().next().map(|k| k.as_str()).unwrap_or(""),
+        _ => {
+            let message = "expected \"Request\" or {\"Request\": ...}";
+            return Err(ApiError::new(ErrorCode::Unprocessable, message));
         }
     };
-    Ok(())
+    if !variant_names::<T>().contains(&variant) {
+        let message = format!("unknown request: {}", variant);
+        return Err(ApiError::new(ErrorCode::Unsupported, message));
+    }
+    serde_json::from_value::<T>(value)
+        .map_err(|e| ApiError::new(ErrorCode::Unprocessable, e.to_string()))
 }
-pub fn handle_client_request(req: ClientRequest, db: &Sqlite) -> anyhow::Result<()> {
-    match req {
-        ClientRequest::GetFullRegistry => {
-            let data = dbm::get_all(db)?;
-            send_hyperware_response(&json!(data))?;
+/// The variants serde accepts for the enum T, as derive(Deserialize) hands
+/// them to the deserializer
+fn variant_names<T: serde::de::DeserializeOwned>() -> &'static [&'static str] {
+    use serde::de::{self, Deserializer, Visitor};
+
+    struct VariantNames<'a>(&'a mut &'static [&'static str]);
+    impl<'de> Deserializer<'de> for VariantNames<'_> {
+        type Error = de::value::Error;
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("not an enum"))
         }
-        ClientRequest::GetCategory(query) => {
-            let data = dbm::get_by_category(db, query.to_string())?;
-            send_hyperware_response(&json!(data))?;
+        fn deserialize_enum<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            variants: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = variants;
+            Err(de::Error::custom("only reading the variant names"))
         }
-        ClientRequest::SearchRegistry(query) => {
-            let data = dbm::search_provider(db, query.to_string())?;
-            send_hyperware_response(&json!(data))?;
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map struct identifier ignored_any
         }
     }
-    Ok(())
+    let mut names: &'static [&'static str] = &[];
+    let _ = T::deserialize(VariantNames(&mut names));
+    names
+}
+fn missing_param(name: &str) -> ApiError {
+    ApiError::new(
+        ErrorCode::BadRequest,
+        format!("missing parameter: {}", name),
+    )
+}
+fn route_client_request(req: ClientRequest, db: &Sqlite) -> ClientResponse {
+    let data = match req {
+        ClientRequest::GetFullRegistry => dbm::get_all(db)?,
+        ClientRequest::GetCategory(query) => dbm::get_by_category(db, query.to_string())?,
+        ClientRequest::SearchRegistry(query) => dbm::search_provider(db, query.to_string())?,
+        ClientRequest::GetProvider(lookup) => {
+            return lookup_provider(db, lookup)?
+                .ok_or(ApiError::new(ErrorCode::NotFound, "no such provider"));
+        }
+        ClientRequest::ListCategories => return Ok(json!(list_categories(db)?)),
+        ClientRequest::ListProviders {
+            category,
+            query,
+            options,
+        } => return Ok(json!(list_page(db, category, query, options)?)),
+    };
+    Ok(json!(data))
 }
 
 fn send_json_response<T: serde::Serialize>(status: StatusCode, data: &T) -> anyhow::Result<()> {
@@ -158,8 +703,138 @@ fn send_json_response<T: serde::Serialize>(status: StatusCode, data: &T) -> anyh
     );
     Ok(())
 }
+fn send_error_response(e: &ApiError) -> anyhow::Result<()> {
+    send_json_response(status_for(&e.code), e)
+}
+fn status_for(code: &ErrorCode) -> StatusCode {
+    match code {
+        ErrorCode::BadRequest | ErrorCode::Unsupported => StatusCode::BAD_REQUEST,
+        ErrorCode::Unprocessable => StatusCode::UNPROCESSABLE_ENTITY,
+        ErrorCode::NotFound => StatusCode::NOT_FOUND,
+        ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+        ErrorCode::Upstream => StatusCode::BAD_GATEWAY,
+        ErrorCode::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+        ErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
 fn send_hyperware_response<T: serde::Serialize>(data: &T) -> anyhow::Result<()> {
     let body = serde_json::to_vec(data)?;
     Response::new().body(body).send()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code_of(body: &str) -> Option<ErrorCode> {
+        parse_request::<ClientRequest>(body.as_bytes())
+            .err()
+            .map(|e| e.code)
+    }
+
+    #[test]
+    fn requests_are_told_apart_by_what_went_wrong() {
+        assert!(code_of(r#""GetFullRegistry""#).is_none());
+        assert!(code_of(r#"{"GetCategory": "weather"}"#).is_none());
+        assert!(matches!(code_of("{"), Some(ErrorCode::BadRequest)));
+        assert!(matches!(
+            code_of(r#"{"DropTables": null}"#),
+            Some(ErrorCode::Unsupported)
+        ));
+        assert!(matches!(
+            code_of(r#""DropTables""#),
+            Some(ErrorCode::Unsupported)
+        ));
+        // a known request with the wrong payload isn't an unknown one
+        assert!(matches!(
+            code_of(r#"{"GetCategory": 7}"#),
+            Some(ErrorCode::Unprocessable)
+        ));
+        assert!(matches!(
+            code_of(r#"{"GetCategory": "a", "SearchRegistry": "b"}"#),
+            Some(ErrorCode::Unprocessable)
+        ));
+        assert!(matches!(code_of("[]"), Some(ErrorCode::Unprocessable)));
+    }
+
+    #[test]
+    fn variant_names_come_from_the_enum() {
+        let names = variant_names::<ClientRequest>();
+        assert!(names.contains(&"GetFullRegistry"));
+        assert!(names.contains(&"ListProviders"));
+        assert!(variant_names::<HttpPostRequest>().contains(&"CallCategory"));
+    }
+
+    #[test]
+    fn error_codes_map_to_their_statuses() {
+        let cases = [
+            (ErrorCode::BadRequest, 400),
+            (ErrorCode::Unsupported, 400),
+            (ErrorCode::NotFound, 404),
+            (ErrorCode::MethodNotAllowed, 405),
+            (ErrorCode::Unprocessable, 422),
+            (ErrorCode::RateLimited, 429),
+            (ErrorCode::Internal, 500),
+            (ErrorCode::Upstream, 502),
+            (ErrorCode::Unavailable, 503),
+            (ErrorCode::Timeout, 504),
+        ];
+        for (code, status) in cases {
+            assert_eq!(status_for(&code).as_u16(), status, "{:?}", code);
+        }
+    }
+
+    fn query(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn list_options_default_to_the_first_page_in_id_order() {
+        let options = list_options_from_query(&HashMap::new()).unwrap();
+        assert!(options.limit.is_none() && options.cursor.is_none());
+        assert!(options.sort.is_none() && options.fields.is_none());
+        assert!(matches!(options.order, SortOrder::Asc));
+        assert!(!options.online_only);
+    }
+
+    #[test]
+    fn list_options_are_read_from_the_query() {
+        let options = list_options_from_query(&query(&[
+            ("limit", "20"),
+            ("cursor", "40"),
+            ("sort", "price"),
+            ("order", "desc"),
+            ("fields", "name, price"),
+            ("online_only", "true"),
+        ]))
+        .unwrap();
+        assert_eq!(options.limit, Some(20));
+        assert_eq!(options.cursor.as_deref(), Some("40"));
+        assert!(matches!(options.sort, Some(SortKey::Price)));
+        assert!(matches!(options.order, SortOrder::Desc));
+        assert_eq!(
+            options.fields,
+            Some(vec!["name".to_string(), "price".to_string()])
+        );
+        assert!(options.online_only);
+    }
+
+    #[test]
+    fn bad_list_options_are_bad_requests() {
+        for (key, value) in [
+            ("limit", "ten"),
+            ("sort", "rating"),
+            ("order", "up"),
+            ("online_only", "yes"),
+        ] {
+            let e = ApiError::from(list_options_from_query(&query(&[(key, value)])).unwrap_err());
+            assert!(matches!(e.code, ErrorCode::BadRequest), "{}={}", key, value);
+        }
+    }
+}
@@ -3,14 +3,18 @@ use hyperware_process_lib::homepage::add_to_homepage;
 use hyperware_process_lib::http::server::{HttpBindingConfig, HttpServer};
 use hyperware_process_lib::logging::{info, init_logging, Level};
 use hyperware_process_lib::sqlite::Sqlite;
-use hyperware_process_lib::{await_message, call_init, Address, Message};
+use hyperware_process_lib::{await_message, call_init, Address, Message, SendError};
 
 mod db;
 mod structs;
 use structs::*;
+#[path = "../../shared/audit_common.rs"]
+mod audit_common;
 mod chain;
+mod failover;
 mod helpers;
 mod http_handlers;
+mod probe;
 
 fn init_http() -> anyhow::Result<HttpServer> {
     let mut http_server = HttpServer::new(5);
@@ -21,6 +25,12 @@ fn init_http() -> anyhow::Result<HttpServer> {
     http_server.bind_http_path("/api/all", http_config.clone())?;
     http_server.bind_http_path("/api/cat", http_config.clone())?;
     http_server.bind_http_path("/api/search", http_config.clone())?;
+    http_server.bind_http_path("/api/categories", http_config.clone())?;
+    http_server.bind_http_path("/api/provider/name/:name", http_config.clone())?;
+    http_server.bind_http_path("/api/provider/hash/:hash", http_config.clone())?;
+    http_server.bind_http_path("/api/provider/id/:id", http_config.clone())?;
+    // who called what, only for us
+    http_server.bind_http_path("/api/audit", http_config.clone())?;
     http_server.bind_http_path(
         "/api/mcp",
         HttpBindingConfig::new(false, false, false, None),
@@ -39,10 +49,13 @@ fn init(our: Address) {
     info!("sqlite loaded");
 
     let mut state = State::load();
+    // keeps the audit_key made for a state that didn't have one
+    state.save();
     let _http_server = init_http().expect("failed to bind paths");
     let db = db::load_db(&our).unwrap();
 
     let mut pending = chain::start_fetch(&mut state, &db);
+    probe::start();
     loop {
         if let Err(e) = main(&our, &mut state, &db, &mut pending) {
             // print_to_terminal(1, "fatal error {e}");
@@ -58,7 +71,10 @@ fn main(
     db: &Sqlite,
     pending: &mut PendingLogs,
 ) -> anyhow::Result<()> {
-    let message = await_message()?;
+    let message = match await_message() {
+        Ok(message) => message,
+        Err(send_error) => return handle_send_error(db, send_error),
+    };
     match message {
         Message::Request { source, body, .. } => {
             handle_request(our, &source, body, state, db, pending)
@@ -88,9 +104,7 @@ fn handle_request(
     } else if process.as_str() == "http-server:distro:sys" {
         http_handlers::handle_frontend(our, &body, state, db)?;
     } else {
-        let request = serde_json::from_slice::<ClientRequest>(&body)?;
-        info!("{:#?}", request);
-        http_handlers::handle_client_request(request, db)?;
+        http_handlers::handle_client_request(&body, db)?;
     }
 
     Ok(())
@@ -121,6 +135,8 @@ fn handle_terminal_debug(
             info!("block: {:#?}", state.last_checkpoint_block);
             let nstate = State::new();
             *state = nstate;
+            // or a restart would bring back the old state over the wiped db
+            state.save();
             info!("block: {:#?}", state.last_checkpoint_block);
             info!("resetting db");
             db::wipe_db(our)?;
@@ -128,10 +144,33 @@ fn handle_terminal_debug(
             let npending = chain::start_fetch(state, db);
             *pending = npending;
         }
+        // audit [caller=..] [provider_name=..] [provider_id=..] [status=ok|<code>] [since=..] [until=..] [limit=..]
+        c if c == "audit" || c.starts_with("audit ") => {
+            let params = audit_common::params_from_words(c.split_whitespace().skip(1));
+            let filter = http_handlers::audit_filter_from_query(&params)?;
+            let rows = db::get_audit(db, &filter)?;
+            info!("{} audit rows\n{:#?}", rows.len(), rows);
+        }
         _ => (),
     }
     Ok(())
 }
+/// A CallProvider that timed out or couldn't reach the provider's node still
+/// owes its HTTP request an answer, and a probe that did marks the node unreachable;
+/// any other failed send stops us as before
+fn handle_send_error(db: &Sqlite, send_error: SendError) -> anyhow::Result<()> {
+    let to_provider = send_error.target().process == "provider:hpn:sortugdev.os";
+    let context = match send_error.context() {
+        Some(context) if to_provider => serde_json::from_slice::<ProviderContext>(context)?,
+        _ => return Err(send_error.into()),
+    };
+    match context {
+        ProviderContext::Call(call) => {
+            http_handlers::handle_provider_send_error(db, send_error.kind(), *call)
+        }
+        ProviderContext::Probe(probe) => probe::handle_unreachable(db, probe),
+    }
+}
 fn handle_response(
     _our: &Address,
     source: &Address,
@@ -143,6 +182,9 @@ fn handle_response(
 ) -> anyhow::Result<()> {
     let process = source.process.to_string();
     match process.as_str() {
+        "timer:distro:sys" if context.as_deref() == Some(probe::TIMER_CONTEXT) => {
+            probe::handle_timer(db)?;
+        }
         "timer:distro:sys" => {
             let is_checkpoint = context == Some(b"checkpoint".to_vec());
             chain::handle_timer(state, db, pending, is_checkpoint)?;
@@ -150,6 +192,16 @@ fn handle_response(
         "eth:distro:sys" => {
             chain::handle_eth_message(state, db, pending, &body)?;
         }
+        // answers to CallProvider, see http_handlers::send_call_provider
+        "provider:hpn:sortugdev.os" => {
+            let context = context.ok_or(anyhow::anyhow!("provider response without context"))?;
+            match serde_json::from_slice::<ProviderContext>(&context)? {
+                ProviderContext::Call(call) => {
+                    http_handlers::handle_provider_response(db, &body, *call)?
+                }
+                ProviderContext::Probe(probe) => probe::handle_pong(db, &body, probe)?,
+            }
+        }
         _ => (),
     };
     Ok(())
@@ -0,0 +1,133 @@
+use hyperware_process_lib::logging::info;
+use hyperware_process_lib::sqlite::Sqlite;
+use hyperware_process_lib::{timer, Address, Request};
+use serde_json::{json, Value};
+
+use crate::db as dbm;
+use crate::helpers::{make_timestamp_ms, make_timestamp_secs};
+use crate::structs::*;
+
+// every PROBE_INTERVAL_MS each node named as a ~provider-id gets a Ping.
+// a provider is online when its node answered and still serves its provider_name.
+// nodes that are offline, or no longer run the provider process, fail to answer in time.
+
+/// context of the probe timer
+pub const TIMER_CONTEXT: &[u8] = b"probe";
+const FIRST_PROBE_MS: u64 = 30 * 1000;
+const PROBE_INTERVAL_MS: u64 = 5 * 60 * 1000;
+const PROBE_TIMEOUT_S: u64 = 10;
+
+pub fn start() {
+    timer::set_timer(FIRST_PROBE_MS, Some(TIMER_CONTEXT.to_vec()));
+}
+
+/// Pings every provider node without waiting on them, then schedules the next round
+pub fn handle_timer(db: &Sqlite) -> anyhow::Result<()> {
+    timer::set_timer(PROBE_INTERVAL_MS, Some(TIMER_CONTEXT.to_vec()));
+    let provider_ids = dbm::get_provider_ids(db)?;
+    info!("probing {} provider nodes", provider_ids.len());
+    for provider_id in provider_ids {
+        let probe = Probe {
+            provider_id: provider_id.clone(),
+            sent_ms: make_timestamp_ms() as u64,
+        };
+        let process = ("provider", "hpn", "sortugdev.os");
+        let res = Request::new()
+            .target(Address::new(provider_id.clone(), process))
+            .body(serde_json::to_vec(&json!("Ping"))?)
+            .expects_response(PROBE_TIMEOUT_S)
+            .context(serde_json::to_vec(&ProviderContext::Probe(probe))?)
+            .send();
+        if let Err(e) = res {
+            info!("couldn't probe {}: {:?}", provider_id, e);
+        }
+    }
+    Ok(())
+}
+
+/// The node answered with the provider names it serves
+pub fn handle_pong(db: &Sqlite, body: &[u8], probe: Probe) -> anyhow::Result<()> {
+    let latency_ms = (make_timestamp_ms() as u64).saturating_sub(probe.sent_ms);
+    let pong = serde_json::from_slice::<Value>(body).unwrap_or_default();
+    let now = make_timestamp_secs();
+    for (hash, online) in online_in(&pong, providers_of(db, &probe.provider_id)?) {
+        dbm::set_reachability(db, hash, online, Some(latency_ms), online.then_some(now))?;
+    }
+    Ok(())
+}
+
+/// (hash, online) of each of the node's providers: online when the pong lists its name
+fn online_in(pong: &Value, providers: Vec<(String, Option<String>)>) -> Vec<(String, bool)> {
+    let served = pong["providers"]
+        .as_array()
+        .map(|names| names.iter().filter_map(|n| n.as_str()).collect::<Vec<_>>())
+        .unwrap_or_default();
+    providers
+        .into_iter()
+        .map(|(hash, name)| {
+            let online = name.is_some_and(|n| served.contains(&n.as_str()));
+            (hash, online)
+        })
+        .collect()
+}
+
+/// The node is offline, doesn't run the provider process or didn't answer in time
+pub fn handle_unreachable(db: &Sqlite, probe: Probe) -> anyhow::Result<()> {
+    info!("provider node {} is unreachable", probe.provider_id);
+    for (hash, _) in providers_of(db, &probe.provider_id)? {
+        dbm::set_reachability(db, hash, false, None, None)?;
+    }
+    Ok(())
+}
+
+/// (hash, provider_name) of every provider the node serves
+fn providers_of(db: &Sqlite, provider_id: &str) -> anyhow::Result<Vec<(String, Option<String>)>> {
+    Ok(dbm::get_by_provider_id(db, provider_id.to_string())?
+        .iter()
+        .filter_map(|row| {
+            let hash = row.get("hash")?.as_str()?.to_string();
+            let name = row
+                .get("provider_name")
+                .and_then(|n| n.as_str())
+                .map(|n| n.to_string());
+            Some((hash, name))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn providers() -> Vec<(String, Option<String>)> {
+        vec![
+            ("0xa".to_string(), Some("Catpics".to_string())),
+            ("0xb".to_string(), Some("Weather".to_string())),
+            ("0xc".to_string(), Some("Stocks".to_string())),
+            ("0xd".to_string(), None),
+        ]
+    }
+
+    #[test]
+    fn only_the_providers_a_pong_lists_are_online() {
+        let pong = json!({ "providers": ["Catpics", "Weather"] });
+        assert_eq!(
+            online_in(&pong, providers()),
+            vec![
+                ("0xa".to_string(), true),
+                ("0xb".to_string(), true),
+                ("0xc".to_string(), false),
+                ("0xd".to_string(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_pong_without_providers_marks_all_offline() {
+        for pong in [json!({}), json!("Pong"), json!({ "providers": [] })] {
+            assert!(online_in(&pong, providers())
+                .iter()
+                .all(|(_, online)| !online));
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use hyperware_process_lib::{eth, get_state, hypermap, println, set_state};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+
+use crate::audit_common::new_audit_key;
 type Namehash = String;
 use std::{
     collections::HashMap,
@@ -8,7 +10,6 @@ use std::{
     time::{SystemTime, UNIX_EPOCH},
 };
 
-use hyperware::process::standard::clear_state;
 wit_bindgen::generate!({
     path: "target/wit",
     world: "hpn-sortugdev-dot-os-v0",
@@ -20,12 +21,15 @@ wit_bindgen::generate!({
 //     HpnMessage, Request as HpnRequest, Response as HpnResponse, SendRequest,
 // };
 
-const HYPERMAP_ADDRESS: &'static str = hypermap::HYPERMAP_ADDRESS;
+const HYPERMAP_ADDRESS: &str = hypermap::HYPERMAP_ADDRESS;
 
 const CHAIN_ID: u64 = hypermap::HYPERMAP_CHAIN_ID; // base
 
 const HYPERMAP_FIRST_BLOCK: u64 = hypermap::HYPERMAP_FIRST_BLOCK; // base
 
+/// hypermap name all categories and providers live under
+pub const HPN_ROOT_NAME: &str = "hpn-testing-beta.os";
+
 pub const DELAY_MS: u64 = 5_000; // 5s
 pub const CHECKPOINT_MS: u64 = 300_000; // 5 minutes
 
@@ -38,7 +42,40 @@ pub enum HttpPostRequest {
         provider_id: String,
         provider_name: String,
         arguments: HashMap<String, Value>,
+        /// forwarded to key-gated providers
+        #[serde(default)]
+        api_key: Option<String>,
+        #[serde(default)]
+        format: ResponseFormat,
+    },
+    /// any provider in the category, best ranked first, the next one if it fails
+    #[serde(rename_all = "camelCase")]
+    CallCategory {
+        category: String,
+        arguments: HashMap<String, Value>,
+        #[serde(default)]
+        api_key: Option<String>,
+        #[serde(default)]
+        format: ResponseFormat,
     },
+    /// providers as MCP tools, inputSchema taken from their ~schema note
+    ListTools {
+        #[serde(default)]
+        category: Option<String>,
+        #[serde(default)]
+        query: Option<String>,
+    },
+}
+
+/// how CallProvider answers
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseFormat {
+    /// the provider's envelope as JSON; binary bodies as they are, with their content type
+    #[default]
+    Raw,
+    /// an MCP tool result, binary bodies base64 encoded in an image or resource block
+    Mcp,
 }
 
 // calls from the Client
@@ -47,16 +84,232 @@ pub enum ClientRequest {
     GetFullRegistry,
     GetCategory(String),
     SearchRegistry(String),
+    GetProvider(ProviderLookup),
+    ListCategories,
+    /// paginated, sortable listing; filters combine with AND
+    ListProviders {
+        category: Option<String>,
+        query: Option<String>,
+        #[serde(default)]
+        options: ListOptions,
+    },
+}
+
+pub const DEFAULT_PAGE_LIMIT: u64 = 50;
+pub const MAX_PAGE_LIMIT: u64 = 500;
+
+/// columns a listing can be projected to with `fields`
+pub const PROVIDER_COLUMNS: [&str; 16] = [
+    "id",
+    "hash",
+    "name",
+    "provider_name",
+    "site",
+    "description",
+    "provider_id",
+    "wallet",
+    "price",
+    "instructions",
+    "schema",
+    "category",
+    "created",
+    "online",
+    "latency_ms",
+    "last_seen",
+];
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub enum SortKey {
+    Created,
+    Name,
+    Price,
+}
+
+/// `asc` or `desc`, the same words as the HTTP `order` parameter
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ListOptions {
+    /// defaults to DEFAULT_PAGE_LIMIT, capped at MAX_PAGE_LIMIT
+    pub limit: Option<u64>,
+    /// `next_cursor` from the previous page
+    pub cursor: Option<String>,
+    pub sort: Option<SortKey>,
+    #[serde(default)]
+    pub order: SortOrder,
+    /// only return these columns
+    pub fields: Option<Vec<String>>,
+    /// leave out providers the last probe didn't reach
+    #[serde(default)]
+    pub online_only: bool,
 }
 
+/// context of every request sent to a provider process
 #[derive(Clone, Debug, Deserialize, Serialize)]
-enum DataKey {
-    /// facts are immutable
-    Fact(eth::Bytes),
-    /// notes are mutable: we store all versions of the note, most recent last
-    /// if indexing full history, this will be the note's full history --
-    /// it is also possible to receive a snapshot and not have updates from before that.
-    Note(Vec<eth::Bytes>),
+pub enum ProviderContext {
+    /// boxed, calls carry their arguments for failover
+    Call(Box<PendingCall>),
+    Probe(Probe),
+}
+
+/// a health probe of one provider node
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Probe {
+    pub provider_id: String,
+    /// unix ms, for latency
+    pub sent_ms: u64,
+}
+
+/// a CallProvider waiting on the provider, sent along as the request's context
+/// so the answer can be turned into the HTTP response whenever it arrives
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PendingCall {
+    pub provider_id: String,
+    pub provider_name: String,
+    pub format: ResponseFormat,
+    /// for the audit row
+    pub caller: String,
+    pub args_digest: String,
+    /// unix seconds
+    pub ts: u64,
+    /// unix ms, for latency
+    pub started_ms: u64,
+    /// set for CallCategory
+    #[serde(default)]
+    pub failover: Option<Failover>,
+}
+
+/// what a CallCategory needs to try the next provider
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Failover {
+    pub category: String,
+    pub arguments: HashMap<String, Value>,
+    pub api_key: Option<String>,
+    /// candidates left, best first
+    pub next: Vec<Candidate>,
+    /// provider names that failed so far
+    pub tried: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Candidate {
+    pub provider_id: String,
+    pub provider_name: String,
+}
+
+pub const DEFAULT_AUDIT_LIMIT: u64 = 100;
+pub const MAX_AUDIT_LIMIT: u64 = 1000;
+
+/// which CallProvider audit rows to read back, newest first
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AuditFilter {
+    /// the client's IP address
+    pub caller: Option<String>,
+    pub provider_name: Option<String>,
+    pub provider_id: Option<String>,
+    /// "ok" or an ErrorCode
+    pub status: Option<String>,
+    /// unix seconds, inclusive
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    /// defaults to DEFAULT_AUDIT_LIMIT, capped at MAX_AUDIT_LIMIT
+    pub limit: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Category {
+    pub name: String,
+    pub hash: String,
+    /// e.g. weather.hpn-testing-beta.os
+    pub full_name: String,
+    pub provider_count: u64,
+    /// last block a mint or note touched this category or its providers
+    pub updated_block: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Page {
+    pub items: Vec<Value>,
+    /// absent on the last page
+    pub next_cursor: Option<String>,
+}
+
+/// exact ways to point at a provider
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum ProviderLookup {
+    /// full hypermap name, e.g. foo.weather.hpn-testing-beta.os
+    Name(String),
+    /// namehash of the provider entry
+    Hash(String),
+    /// the ~provider-id node; returns every provider served by that node
+    ProviderId(String),
+}
+
+// responses to the Client, every ClientRequest gets one
+pub type ClientResponse = Result<Value, ApiError>;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum ErrorCode {
+    /// body wasn't valid JSON, or a parameter is missing or malformed
+    BadRequest,
+    /// valid JSON that doesn't match the request shape
+    Unprocessable,
+    /// well-formed request this indexer doesn't know about
+    Unsupported,
+    NotFound,
+    MethodNotAllowed,
+    /// the provider answered with an error or couldn't be reached
+    Upstream,
+    /// the provider didn't answer in time
+    Timeout,
+    /// the provider is rate limiting us, details hold its retry_after_ms
+    RateLimited,
+    /// the provider stopped calling a failing upstream for a while
+    Unavailable,
+    Internal,
+}
+
+/// error envelope for both the process API and the HTTP API
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+            details: None,
+        }
+    }
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+}
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+impl std::error::Error for ApiError {}
+impl From<anyhow::Error> for ApiError {
+    /// keeps ApiErrors raised deeper down, anything else is internal
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<ApiError>() {
+            Ok(api_error) => api_error,
+            Err(e) => Self::new(ErrorCode::Internal, e),
+        }
+    }
 }
 
 type Name = String;
@@ -71,7 +324,7 @@ pub struct Provider {
     pub facts: HashMap<String, Vec<String>>,
 }
 
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct State {
     /// the chain id we are indexing
     pub chain_id: u64,
@@ -87,13 +340,34 @@ pub struct State {
     /// last saved checkpoint block
     pub last_checkpoint_block: u64,
     pub logging_started: u64,
+    /// keys the argument digests in the audit table, see audit_common::arguments_digest
+    #[serde(default = "new_audit_key")]
+    pub audit_key: String,
+}
+
+/// what the terminal's `state` prints, without the audit_key
+impl std::fmt::Debug for State {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("State")
+            .field("chain_id", &self.chain_id)
+            .field("contract_address", &self.contract_address)
+            .field("hypermap", &self.hypermap)
+            .field("root_hash", &self.root_hash)
+            .field("categories", &self.categories)
+            .field("providers", &self.providers)
+            .field("names", &self.names)
+            .field("last_checkpoint_block", &self.last_checkpoint_block)
+            .field("logging_started", &self.logging_started)
+            .field("audit_key", &"<redacted>")
+            .finish()
+    }
 }
 
 impl State {
     pub fn new() -> Self {
         let hypermap = hypermap::Hypermap::default(60);
 
-        let new_state = Self {
+        Self {
             chain_id: CHAIN_ID,
             contract_address: eth::Address::from_str(HYPERMAP_ADDRESS).unwrap(),
             hypermap,
@@ -103,8 +377,8 @@ impl State {
             names: HashMap::from([(String::new(), hypermap::HYPERMAP_ROOT_HASH.to_string())]),
             last_checkpoint_block: HYPERMAP_FIRST_BLOCK,
             logging_started: get_now(),
-        };
-        new_state
+            audit_key: new_audit_key(),
+        }
     }
 
     pub fn load() -> Self {
@@ -120,11 +394,6 @@ impl State {
         }
     }
 
-    /// Reset by removing the checkpoint and reloading fresh state
-    pub fn reset(&self) {
-        clear_state();
-    }
-
     /// Saves a checkpoint, serializes to the current block
     pub fn save(&mut self) {
         match serde_json::to_vec(self) {
@@ -141,13 +410,6 @@ impl State {
     // }
 }
 
-// HTTP requests from frontend
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
-pub enum FrontendRequest {
-    Get,
-    Other,
-}
-
 pub fn get_now() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
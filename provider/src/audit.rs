@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyperware_process_lib::logging::error;
+use hyperware_process_lib::sqlite::Sqlite;
+use serde_json::Value;
+
+use crate::audit_common::arguments_digest;
+use crate::db as dbm;
+use crate::structs::*;
+
+// one row per MCP call, whatever became of it, in the audit table of our sqlite db.
+// rows are only ever inserted. arguments are kept as a digest, not in the clear:
+// an HMAC keyed with this node's audit_key, so a small set of likely arguments
+// (a city, a ticker) can't be hashed and matched without the key.
+
+pub const DEFAULT_AUDIT_LIMIT: u64 = 100;
+pub const MAX_AUDIT_LIMIT: u64 = 1000;
+
+/// which rows to read back, newest first
+#[derive(Clone, Debug, Default)]
+pub struct AuditFilter {
+    pub caller: Option<String>,
+    pub provider_name: Option<String>,
+    /// "ok", "cached" or an error code
+    pub status: Option<String>,
+    /// unix seconds, inclusive
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub limit: u64,
+}
+impl AuditFilter {
+    /// From query params or terminal `key=value` words
+    pub fn from_params(params: &HashMap<String, String>) -> anyhow::Result<Self> {
+        let number = |key: &str| -> anyhow::Result<Option<u64>> {
+            match params.get(key) {
+                Some(v) => Ok(Some(v.parse().map_err(|_| anyhow::anyhow!("bad {}", key))?)),
+                None => Ok(None),
+            }
+        };
+        Ok(Self {
+            caller: params.get("caller").cloned(),
+            provider_name: params.get("provider_name").cloned(),
+            status: params.get("status").cloned(),
+            since: number("since")?,
+            until: number("until")?,
+            limit: number("limit")?
+                .unwrap_or(DEFAULT_AUDIT_LIMIT)
+                .min(MAX_AUDIT_LIMIT),
+        })
+    }
+}
+
+/// Appends a row for one call; a failing write is logged, never fails the call
+pub fn record(
+    db: &Sqlite,
+    key: &str,
+    caller: &str,
+    provider_name: &str,
+    arguments: &HashMap<String, Value>,
+    res: &anyhow::Result<ProviderResponse>,
+    latency_ms: u64,
+) {
+    let (status, http_status, bytes) = match res {
+        Ok(r) if r.cached => ("cached".to_string(), Some(r.status), r.body_len()),
+        Ok(r) => ("ok".to_string(), Some(r.status), r.body_len()),
+        Err(e) => {
+            let e = e.downcast_ref::<ProviderError>();
+            let code = e
+                .map(|e| format!("{:?}", e.code))
+                .unwrap_or("Internal".to_string());
+            let http_status = e
+                .and_then(|e| e.details.as_ref())
+                .and_then(|d| d["status"].as_u64())
+                .map(|s| s as u16);
+            (code, http_status, 0)
+        }
+    };
+    let row = dbm::AuditRow {
+        ts: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+        caller: caller.to_string(),
+        provider: provider_name.to_string(),
+        args_digest: arguments_digest(key, arguments),
+        status,
+        http_status,
+        latency_ms,
+        bytes: bytes as u64,
+    };
+    if let Err(e) = dbm::insert_audit(db, row) {
+        error!("audit write failed: {:?}", e);
+    }
+}
@@ -0,0 +1,75 @@
+use hyperware_process_lib::Address;
+use sha2::{Digest, Sha256};
+
+use crate::structs::*;
+
+/// A provider's access: the terminal override, else its definition's.
+/// Providers are public unless they say otherwise or take payments
+pub fn access_for(state: &State, provider_name: &str) -> Access {
+    if let Some(access) = state.access.get(provider_name) {
+        return access.clone();
+    }
+    match state.definitions.get(provider_name) {
+        Some(def) => match (&def.access, &def.payment) {
+            (Some(access), _) => access.clone(),
+            (None, Some(_)) => Access::Key,
+            (None, None) => Access::Public,
+        },
+        None => Access::default(),
+    }
+}
+
+/// Compares in constant time, so response times don't give away how much of a key was right.
+/// Hashing first keeps the lengths from showing too
+pub fn keys_match(given: &str, ours: &str) -> bool {
+    let (given, ours) = (Sha256::digest(given), Sha256::digest(ours));
+    let diff = given
+        .iter()
+        .zip(ours.iter())
+        .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+    std::hint::black_box(diff) == 0
+}
+
+/// Lets a call through if the provider is public, the caller is our own node,
+/// the caller sent a key we issued, or the caller's node has been granted access.
+pub fn authorize(
+    our: &Address,
+    source: &Address,
+    provider_name: &str,
+    api_key: Option<&str>,
+    state: &State,
+) -> Result<(), ProviderError> {
+    if source.node() == our.node() {
+        return Ok(());
+    }
+    if let Access::Public = access_for(state, provider_name) {
+        return Ok(());
+    }
+    let allowed = state.in_keys.values().any(|user| {
+        let key_matches = api_key.is_some_and(|k| keys_match(k, &user.api_key));
+        let node_matches = user.node.as_deref() == Some(source.node());
+        (key_matches || node_matches) && user.can_call(provider_name)
+    });
+    if allowed {
+        Ok(())
+    } else {
+        let message = match api_key {
+            None => format!("{} requires an API key", provider_name),
+            Some(_) => format!("API key not valid for {}", provider_name),
+        };
+        Err(ProviderError::new(ProviderErrorCode::Unauthorized, message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_match_only_themselves() {
+        assert!(keys_match("1ef55da5e2844b19", "1ef55da5e2844b19"));
+        assert!(!keys_match("1ef55da5e2844b18", "1ef55da5e2844b19"));
+        assert!(!keys_match("1ef55da5", "1ef55da5e2844b19"));
+        assert!(!keys_match("", "1ef55da5e2844b19"));
+    }
+}
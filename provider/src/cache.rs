@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyperware_process_lib::logging::error;
+use hyperware_process_lib::sqlite::Sqlite;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::audit_common::normalized_arguments;
+use crate::db as dbm;
+use crate::structs::*;
+
+// opt-in per provider with `cache_ttl_s` in its definition.
+// entries are keyed by provider name plus the arguments with their keys sorted,
+// so the same call sent with its arguments in another order hits too.
+// only successful answers are kept, and a failing cache never fails a call.
+// hit and miss counters live in memory only, a restart zeroes them.
+
+/// hit and miss counters for one provider
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn ttl(state: &State, provider_name: &str) -> Option<u64> {
+    state
+        .definitions
+        .get(provider_name)
+        .and_then(|def| def.cache_ttl_s)
+        .filter(|ttl| *ttl > 0)
+}
+
+/// sha256 of the provider name and the normalized arguments
+pub fn cache_key(provider_name: &str, arguments: &HashMap<String, Value>) -> String {
+    let normalized = normalized_arguments(arguments);
+    let digest = Sha256::digest(format!("{}\n{}", provider_name, normalized).as_bytes());
+    hex::encode(digest)
+}
+
+/// A fresh cached answer, None for providers that don't cache
+pub fn lookup(
+    db: &Sqlite,
+    state: &mut State,
+    provider_name: &str,
+    arguments: &HashMap<String, Value>,
+) -> Option<ProviderResponse> {
+    ttl(state, provider_name)?;
+    let key = cache_key(provider_name, arguments);
+    let cached = match dbm::get_cached(db, &key) {
+        Ok(row) => row.and_then(|row| fresh_response(&row, now())),
+        Err(e) => {
+            error!("cache read failed: {:?}", e);
+            None
+        }
+    };
+    count(state, provider_name, cached.is_some());
+    cached
+}
+
+/// The answer kept in a cache row, None once it expired
+fn fresh_response(row: &HashMap<String, Value>, now: u64) -> Option<ProviderResponse> {
+    if row.get("expires")?.as_u64()? <= now {
+        return None;
+    }
+    let response = row.get("response")?.as_str()?;
+    let mut response = serde_json::from_str::<ProviderResponse>(response).ok()?;
+    let blob = row.get("blob").and_then(|b| b.as_str());
+    response.blob = blob.and_then(|b| hex::decode(b).ok());
+    response.cached = true;
+    Some(response)
+}
+
+fn count(state: &mut State, provider_name: &str, hit: bool) {
+    let stats = state
+        .cache_stats
+        .entry(provider_name.to_string())
+        .or_default();
+    if hit {
+        stats.hits += 1;
+    } else {
+        stats.misses += 1;
+    }
+}
+
+/// Keeps a successful answer for the provider's TTL
+pub fn store(
+    db: &Sqlite,
+    state: &State,
+    provider_name: &str,
+    arguments: &HashMap<String, Value>,
+    response: &ProviderResponse,
+) {
+    let Some(ttl) = ttl(state, provider_name) else {
+        return;
+    };
+    let now = now();
+    let res = serde_json::to_string(response)
+        .map_err(anyhow::Error::from)
+        .and_then(|json| {
+            dbm::delete_expired(db, now)?;
+            dbm::put_cached(
+                db,
+                &cache_key(provider_name, arguments),
+                provider_name,
+                json,
+                response.blob.as_ref().map(hex::encode),
+                now + ttl,
+            )
+        });
+    if let Err(e) = res {
+        error!("cache write failed: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::definitions::ProviderDef;
+    use serde_json::json;
+
+    fn caching(ttl: Option<u64>) -> State {
+        let mut state = State::new();
+        let def = serde_json::from_value::<ProviderDef>(json!({
+            "name": "weather",
+            "url": "https://api.example.com/v1",
+            "cache_ttl_s": ttl,
+        }))
+        .unwrap();
+        state.definitions.insert("weather".to_string(), def);
+        state
+    }
+
+    fn row(expires: u64, blob: Option<&str>) -> HashMap<String, Value> {
+        let response = ProviderResponse::new(200, HashMap::new(), b"{}".to_vec(), 12);
+        serde_json::from_value(json!({
+            "response": serde_json::to_string(&response).unwrap(),
+            "blob": blob,
+            "expires": expires,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn the_same_call_in_any_order_hits_the_same_entry() {
+        let a = HashMap::from([
+            ("city".to_string(), json!("Lisbon")),
+            ("days".to_string(), json!(3)),
+        ]);
+        let b = HashMap::from([
+            ("days".to_string(), json!(3)),
+            ("city".to_string(), json!("Lisbon")),
+        ]);
+        let other = HashMap::from([("city".to_string(), json!("Porto"))]);
+        assert_eq!(cache_key("weather", &a), cache_key("weather", &b));
+        assert_ne!(cache_key("weather", &a), cache_key("weather", &other));
+        assert_ne!(cache_key("weather", &a), cache_key("forecast", &a));
+    }
+
+    #[test]
+    fn entries_are_served_until_they_expire() {
+        let hit = fresh_response(&row(1_010, Some("ff00")), 1_000).unwrap();
+        assert!(hit.cached);
+        assert_eq!(hit.status, 200);
+        assert_eq!(hit.blob, Some(vec![0xff, 0x00]));
+        assert!(fresh_response(&row(1_000, None), 1_000).is_none());
+        assert!(fresh_response(&row(900, None), 1_000).is_none());
+    }
+
+    #[test]
+    fn only_providers_with_a_ttl_are_cached() {
+        assert_eq!(ttl(&caching(Some(60)), "weather"), Some(60));
+        assert_eq!(ttl(&caching(Some(0)), "weather"), None);
+        assert_eq!(ttl(&caching(None), "weather"), None);
+        assert_eq!(ttl(&caching(Some(60)), "finnhub"), None);
+    }
+
+    #[test]
+    fn hits_and_misses_are_counted_per_provider() {
+        let mut state = caching(Some(60));
+        count(&mut state, "weather", true);
+        count(&mut state, "weather", false);
+        count(&mut state, "weather", true);
+        count(&mut state, "finnhub", false);
+        let weather = &state.cache_stats["weather"];
+        assert_eq!((weather.hits, weather.misses), (2, 1));
+        let finnhub = &state.cache_stats["finnhub"];
+        assert_eq!((finnhub.hits, finnhub.misses), (0, 1));
+    }
+}
@@ -0,0 +1,235 @@
+use anyhow::Result;
+use hyperware_process_lib::{
+    logging::info,
+    sqlite::{self, Sqlite},
+    Address,
+};
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::audit::AuditFilter;
+use crate::usage::Usage;
+
+const DB_NAME: &str = "hpn-provider";
+
+pub fn open_db(our: &Address) -> Result<Sqlite> {
+    sqlite::open(our.package_id(), DB_NAME, None)
+}
+
+pub fn load_db(our: &Address) -> Result<Sqlite> {
+    let db = open_db(our)?;
+    if !check_schema(&db) {
+        write_db_schema(&db)?;
+    }
+    Ok(db)
+}
+
+pub fn check_schema(db: &Sqlite) -> bool {
+    let required = ["cache", "audit", "usage"];
+    let statement = "SELECT name from sqlite_master WHERE type='table';".to_string();
+    match db.read(statement, vec![]) {
+        Err(_) => false,
+        Ok(data) => {
+            let tables = data
+                .iter()
+                .filter_map(|row| row.get("name").and_then(|n| n.as_str()))
+                .collect::<Vec<_>>();
+            info!("sql tables:{:?}", tables);
+            required.iter().all(|t| tables.contains(t))
+        }
+    }
+}
+
+pub fn write_db_schema(db: &Sqlite) -> Result<()> {
+    let tx_id = db.begin_tx()?;
+    // blob holds binary bodies, hex encoded
+    let s0 = r#"
+        CREATE TABLE IF NOT EXISTS cache(
+          key TEXT PRIMARY KEY,
+          provider TEXT NOT NULL,
+          response TEXT NOT NULL,
+          blob TEXT,
+          expires INTEGER NOT NULL
+        );"#
+    .to_string();
+    let s1 = "CREATE INDEX IF NOT EXISTS cache_provider ON cache(provider);".to_string();
+    // append only, see audit.rs
+    let s2 = r#"
+        CREATE TABLE IF NOT EXISTS audit(
+          id INTEGER PRIMARY KEY AUTOINCREMENT,
+          ts INTEGER NOT NULL,
+          caller TEXT NOT NULL,
+          provider TEXT NOT NULL,
+          args_digest TEXT NOT NULL,
+          status TEXT NOT NULL,
+          http_status INTEGER,
+          latency_ms INTEGER NOT NULL,
+          bytes INTEGER NOT NULL
+        );"#
+    .to_string();
+    let s3 = "CREATE INDEX IF NOT EXISTS audit_caller ON audit(caller, provider);".to_string();
+    // counters, see usage.rs
+    let s4 = r#"
+        CREATE TABLE IF NOT EXISTS usage(
+          caller TEXT NOT NULL,
+          provider TEXT NOT NULL,
+          calls INTEGER NOT NULL,
+          bytes INTEGER NOT NULL,
+          errors INTEGER NOT NULL,
+          cache_hits INTEGER NOT NULL,
+          day INTEGER NOT NULL,
+          day_calls INTEGER NOT NULL,
+          PRIMARY KEY (caller, provider)
+        );"#
+    .to_string();
+    db.write(s0, vec![], Some(tx_id))?;
+    db.write(s1, vec![], Some(tx_id))?;
+    db.write(s2, vec![], Some(tx_id))?;
+    db.write(s3, vec![], Some(tx_id))?;
+    db.write(s4, vec![], Some(tx_id))?;
+    db.commit_tx(tx_id)
+}
+
+pub struct AuditRow {
+    pub ts: u64,
+    pub caller: String,
+    pub provider: String,
+    pub args_digest: String,
+    pub status: String,
+    pub http_status: Option<u16>,
+    pub latency_ms: u64,
+    pub bytes: u64,
+}
+
+// reads
+pub fn get_audit(db: &Sqlite, filter: &AuditFilter) -> Result<Vec<HashMap<String, Value>>> {
+    let mut conditions = vec![];
+    let mut params = vec![];
+    let mut add = |condition: &str, value: Value| {
+        params.push(value);
+        conditions.push(format!("{} ?{}", condition, params.len()));
+    };
+    if let Some(caller) = &filter.caller {
+        add("caller =", Value::from(caller.as_str()));
+    }
+    if let Some(provider_name) = &filter.provider_name {
+        add("provider =", Value::from(provider_name.as_str()));
+    }
+    if let Some(status) = &filter.status {
+        add("status =", Value::from(status.as_str()));
+    }
+    if let Some(since) = filter.since {
+        add("ts >=", Value::from(since));
+    }
+    if let Some(until) = filter.until {
+        add("ts <=", Value::from(until));
+    }
+    let where_clause = match conditions.is_empty() {
+        true => String::new(),
+        false => format!("WHERE {}", conditions.join(" AND ")),
+    };
+    params.push(Value::from(filter.limit));
+    let s = format!(
+        "SELECT * FROM audit {} ORDER BY id DESC LIMIT ?{}",
+        where_clause,
+        params.len()
+    );
+    db.read(s, params)
+}
+
+pub fn get_usage(
+    db: &Sqlite,
+    caller: Option<&str>,
+    provider: Option<&str>,
+) -> Result<Vec<HashMap<String, Value>>> {
+    let s = r#"
+        SELECT * FROM usage
+        WHERE (?1 IS NULL OR caller = ?1) AND (?2 IS NULL OR provider = ?2)
+        ORDER BY caller, provider
+        "#
+    .to_string();
+    db.read(s, vec![Value::from(caller), Value::from(provider)])
+}
+
+pub fn get_cached(db: &Sqlite, key: &str) -> Result<Option<HashMap<String, Value>>> {
+    let s = "SELECT response, blob, expires FROM cache WHERE key = ?1".to_string();
+    Ok(db.read(s, vec![Value::from(key)])?.into_iter().next())
+}
+
+// writes
+pub fn put_cached(
+    db: &Sqlite,
+    key: &str,
+    provider: &str,
+    response: String,
+    blob: Option<String>,
+    expires: u64,
+) -> Result<()> {
+    let s = r#"
+        INSERT OR REPLACE INTO cache(key, provider, response, blob, expires)
+        VALUES (?1, ?2, ?3, ?4, ?5);
+        "#
+    .to_string();
+    let p = vec![
+        Value::from(key),
+        Value::from(provider),
+        Value::from(response),
+        Value::from(blob),
+        Value::from(expires),
+    ];
+    db.write(s, p, None)
+}
+
+pub fn insert_audit(db: &Sqlite, row: AuditRow) -> Result<()> {
+    let s = r#"
+        INSERT INTO audit(ts, caller, provider, args_digest, status, http_status, latency_ms, bytes)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8);
+        "#
+    .to_string();
+    let p = vec![
+        Value::from(row.ts),
+        Value::from(row.caller),
+        Value::from(row.provider),
+        Value::from(row.args_digest),
+        Value::from(row.status),
+        Value::from(row.http_status),
+        Value::from(row.latency_ms),
+        Value::from(row.bytes),
+    ];
+    db.write(s, p, None)
+}
+
+pub fn put_usage(db: &Sqlite, caller: &str, provider: &str, usage: &Usage) -> Result<()> {
+    let s = r#"
+        INSERT OR REPLACE INTO usage(caller, provider, calls, bytes, errors, cache_hits, day, day_calls)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8);
+        "#
+    .to_string();
+    let p = vec![
+        Value::from(caller),
+        Value::from(provider),
+        Value::from(usage.calls),
+        Value::from(usage.bytes),
+        Value::from(usage.errors),
+        Value::from(usage.cache_hits),
+        Value::from(usage.day),
+        Value::from(usage.day_calls),
+    ];
+    db.write(s, p, None)
+}
+
+pub fn delete_expired(db: &Sqlite, now: u64) -> Result<()> {
+    let s = "DELETE FROM cache WHERE expires <= ?1".to_string();
+    db.write(s, vec![Value::from(now)], None)
+}
+
+/// Empties the cache of one provider, or all of it
+pub fn flush_cache(db: &Sqlite, provider: Option<&str>) -> Result<()> {
+    match provider {
+        Some(provider) => {
+            let s = "DELETE FROM cache WHERE provider = ?1".to_string();
+            db.write(s, vec![Value::from(provider)], None)
+        }
+        None => db.write("DELETE FROM cache".to_string(), vec![], None),
+    }
+}
@@ -0,0 +1,562 @@
+use std::collections::HashMap;
+
+use hyperware_process_lib::http::Method;
+use hyperware_process_lib::logging::{error, info};
+use hyperware_process_lib::vfs::{create_drive, create_file, open_dir, open_file, remove_file};
+use hyperware_process_lib::Address;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use url::Url;
+
+use crate::payments::PaymentTerms;
+use crate::publish::Listing;
+use crate::retry::RetryPolicy;
+use crate::schema;
+use crate::secrets::Secrets;
+use crate::structs::Access;
+
+// provider definitions are JSON or TOML files, one provider per file.
+// shipped ones live in the package drive under providers/,
+// operator-added ones in the "providers" drive, which wins on name clashes.
+// ex. (pkg/providers/weatherapi.json):
+// {
+//   "name": "weatherapi",
+//   "method": "GET",
+//   "url": "https://api.weatherapi.com/v1/current.json?key={secret}&q={query}",
+//   "secret": "WEATHER_API_KEY",
+//   "arguments": { "query": { "required": true } }
+// }
+// a POST with a JSON body:
+// {
+//   "name": "example",
+//   "method": "POST",
+//   "url": "https://example.com/v1/search",
+//   "headers": { "Authorization": "Bearer {secret}" },
+//   "body": { "q": "{query}", "limit": "{limit}" },
+//   "arguments": { "query": { "required": true }, "limit": { "default": 10 } }
+// }
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProviderDef {
+    /// what callers put in MCPRequest.provider_name
+    pub name: String,
+    #[serde(default = "default_method")]
+    pub method: String,
+    /// `{argument}` and `{secret}` placeholders are filled in per call,
+    /// in path segments and query values only. literal parts are percent-encoded
+    /// along with the values, so write them unescaped (a space, not `%20`)
+    pub url: String,
+    /// upstream hosts calls may go to, `*.example.com` matches subdomains.
+    /// the url template's host when left empty
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+    /// let calls reach loopback, private and link-local addresses
+    #[serde(default)]
+    pub allow_private: bool,
+    /// header values take the same placeholders as the url
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// request body template, text or JSON, taking the same placeholders.
+    /// a JSON string that is exactly one `{argument}` becomes the argument's JSON value
+    pub body: Option<Value>,
+    /// Content-Type of the body. JSON bodies default to application/json,
+    /// application/x-www-form-urlencoded sends a JSON object as form fields
+    pub content_type: Option<String>,
+    /// which stored secret fills `{secret}`
+    pub secret: Option<String>,
+    #[serde(default)]
+    pub arguments: HashMap<String, ArgumentDef>,
+    /// JSON Schema for the arguments object, built from `arguments` when left out.
+    /// published in the ~schema note and used as the MCP tool's inputSchema
+    pub schema: Option<Value>,
+    /// "public" or "key". public when left out, unless the definition takes payments
+    pub access: Option<Access>,
+    /// lets callers buy keys by paying on chain
+    pub payment: Option<PaymentTerms>,
+    /// calls per caller per day
+    pub daily_limit: Option<u64>,
+    /// seconds one upstream attempt may take, 60 when left out
+    pub timeout_s: Option<u64>,
+    /// retries, backoff and circuit breaker, see retry.rs
+    pub retry: Option<RetryPolicy>,
+    /// seconds identical calls are answered from the cache, no caching when left out
+    pub cache_ttl_s: Option<u64>,
+    /// description, site and instructions notes written by `publish`
+    pub listing: Option<Listing>,
+    /// the file it was loaded from
+    #[serde(skip)]
+    pub path: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ArgumentDef {
+    #[serde(default)]
+    pub required: bool,
+    /// used when the caller leaves the argument out
+    pub default: Option<Value>,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+const FORM_CONTENT_TYPE: &str = "application/x-www-form-urlencoded";
+const PKG_DIR: &str = "providers";
+const DRIVE: &str = "providers";
+
+/// Reads every definition, shipped ones first so operator files override them
+pub fn load_definitions(our: &Address) -> HashMap<String, ProviderDef> {
+    let mut defs = HashMap::new();
+    let dirs = [
+        create_drive(our.package_id(), "pkg", None).map(|d| format!("{}/{}", d, PKG_DIR)),
+        create_drive(our.package_id(), DRIVE, None),
+    ];
+    for dir in dirs {
+        let dir = match dir {
+            Ok(d) => d,
+            Err(e) => {
+                error!("no provider definitions drive: {:?}", e);
+                continue;
+            }
+        };
+        let entries = match open_dir(&dir, false, None).and_then(|d| d.read()) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries {
+            let file_name = entry
+                .path
+                .rsplit('/')
+                .next()
+                .unwrap_or_default()
+                .to_string();
+            match read_definition(&format!("{}/{}", dir, file_name)) {
+                Ok(Some(def)) => {
+                    info!("loaded provider definition {}", def.name);
+                    defs.insert(def.name.clone(), def);
+                }
+                Ok(None) => (),
+                Err(e) => error!("bad provider definition {}: {:?}", file_name, e),
+            }
+        }
+    }
+    defs
+}
+
+fn read_definition(path: &str) -> anyhow::Result<Option<ProviderDef>> {
+    let bytes = open_file(path, false, None)?.read()?;
+    let def: ProviderDef = if path.ends_with(".json") {
+        serde_json::from_slice(&bytes)?
+    } else if path.ends_with(".toml") {
+        toml::from_str(std::str::from_utf8(&bytes)?)?
+    } else {
+        return Ok(None);
+    };
+    def.check()?;
+    Ok(Some(ProviderDef {
+        path: Some(path.to_string()),
+        ..def
+    }))
+}
+
+/// Parses a JSON definition and stores it in the providers drive,
+/// replacing the drive file the same name was loaded from
+pub fn save_definition(
+    our: &Address,
+    defs: &HashMap<String, ProviderDef>,
+    json: &str,
+) -> anyhow::Result<ProviderDef> {
+    let def = serde_json::from_str::<ProviderDef>(json)?;
+    def.check()?;
+    let drive = create_drive(our.package_id(), DRIVE, None)?;
+    let path = format!("{}/{}.json", drive, file_stem(&def.name));
+    let file = create_file(&path, None)?;
+    file.write(&serde_json::to_vec_pretty(&def)?)?;
+    if let Some(old) = added_path(&drive, defs, &def.name).filter(|old| *old != path) {
+        remove_file(&old, None)?;
+    }
+    Ok(def)
+}
+
+/// Removes an operator-added definition, whichever file it was loaded from;
+/// shipped ones can only be overridden
+pub fn delete_definition(
+    our: &Address,
+    defs: &HashMap<String, ProviderDef>,
+    name: &str,
+) -> anyhow::Result<()> {
+    let drive = create_drive(our.package_id(), DRIVE, None)?;
+    let path =
+        added_path(&drive, defs, name).ok_or(anyhow::anyhow!("no added definition {}", name))?;
+    remove_file(&path, None)?;
+    Ok(())
+}
+
+/// The providers drive file a definition was loaded from, None for shipped ones
+fn added_path(drive: &str, defs: &HashMap<String, ProviderDef>, name: &str) -> Option<String> {
+    defs.get(name)?
+        .path
+        .clone()
+        .filter(|path| path.starts_with(&format!("{}/", drive)))
+}
+
+fn file_stem(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
+
+/// What a definition resolves to for one call
+#[derive(Clone)]
+pub struct PreparedCall {
+    pub method: Method,
+    pub url: Url,
+    pub headers: HashMap<String, String>,
+    /// empty when the definition has no body
+    pub body: Vec<u8>,
+}
+
+impl ProviderDef {
+    pub fn input_schema(&self) -> Value {
+        if let Some(schema) = &self.schema {
+            return schema.clone();
+        }
+        let mut required = self
+            .arguments
+            .iter()
+            .filter(|(_, arg)| arg.required)
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        required.sort();
+        let properties = self
+            .arguments
+            .keys()
+            .map(|name| {
+                (
+                    name.clone(),
+                    json!({"type": ["string", "number", "boolean"]}),
+                )
+            })
+            .collect::<serde_json::Map<_, _>>();
+        json!({
+            "type": "object",
+            "properties": properties,
+            "required": required,
+        })
+    }
+
+    /// Refuses definitions that can't be served: a bad method or url template,
+    /// or a schema using keywords the validator doesn't enforce
+    pub fn check(&self) -> anyhow::Result<()> {
+        self.method()?;
+        fill_url(&self.url, &HashMap::new())?;
+        schema::check_supported(&self.input_schema())
+            .map_err(|e| anyhow::anyhow!("schema of {}: {}", self.name, e))
+    }
+
+    /// Hosts calls may go to, see egress.rs
+    pub fn allowed_hosts(&self) -> Vec<String> {
+        if !self.allowed_hosts.is_empty() {
+            return self.allowed_hosts.clone();
+        }
+        fill_url(&self.url, &HashMap::new())
+            .ok()
+            .and_then(|url| url.host_str().map(|h| h.to_lowercase()))
+            .into_iter()
+            .collect()
+    }
+
+    pub fn method(&self) -> anyhow::Result<Method> {
+        Method::from_bytes(self.method.to_uppercase().as_bytes())
+            .map_err(|_| anyhow::anyhow!("bad method {} for {}", self.method, self.name))
+    }
+
+    /// Fills the templates with the caller's arguments and our secret
+    pub fn prepare(
+        &self,
+        arguments: &HashMap<String, Value>,
+        secrets: &Secrets,
+    ) -> anyhow::Result<PreparedCall> {
+        let mut values = HashMap::new();
+        let mut raw = HashMap::new();
+        for (name, arg) in &self.arguments {
+            let value = match arguments.get(name).or(arg.default.as_ref()) {
+                Some(v) => v.clone(),
+                None if arg.required => {
+                    return Err(anyhow::anyhow!("missing argument: {}", name));
+                }
+                None => Value::Null,
+            };
+            values.insert(name.clone(), argument_string(&value));
+            raw.insert(name.clone(), value);
+        }
+        if let Some(secret) = &self.secret {
+            let key = secrets
+                .get(secret)
+                .ok_or(anyhow::anyhow!("no outgoing API KEY"))?;
+            values.insert("secret".to_string(), key.clone());
+        }
+        let fill = |template: &str| fill_template(template, &values);
+        let mut headers = self
+            .headers
+            .iter()
+            .map(|(k, v)| (k.clone(), fill(v)))
+            .collect::<HashMap<_, _>>();
+        let (body, content_type) = self.encode_body(&values, &raw)?;
+        let has_content_type = headers
+            .keys()
+            .any(|k| k.eq_ignore_ascii_case("content-type"));
+        if let (Some(content_type), false) = (content_type, has_content_type) {
+            headers.insert("Content-Type".to_string(), content_type);
+        }
+        Ok(PreparedCall {
+            method: self.method()?,
+            url: fill_url(&self.url, &values)?,
+            headers,
+            body,
+        })
+    }
+
+    /// The filled in body and the Content-Type to send it with
+    fn encode_body(
+        &self,
+        values: &HashMap<String, String>,
+        raw: &HashMap<String, Value>,
+    ) -> anyhow::Result<(Vec<u8>, Option<String>)> {
+        let content_type = self.content_type.clone();
+        match &self.body {
+            None => Ok((vec![], None)),
+            Some(Value::String(template)) => Ok((
+                fill_template(template, values).into_bytes(),
+                content_type.or(Some("text/plain".to_string())),
+            )),
+            Some(template) => {
+                let filled = fill_json(template, values, raw);
+                if content_type.as_deref() != Some(FORM_CONTENT_TYPE) {
+                    let content_type = content_type.or(Some("application/json".to_string()));
+                    return Ok((serde_json::to_vec(&filled)?, content_type));
+                }
+                let fields = filled.as_object().ok_or(anyhow::anyhow!(
+                    "form body of {} isn't an object",
+                    self.name
+                ))?;
+                let mut form = url::form_urlencoded::Serializer::new(String::new());
+                for (k, v) in fields {
+                    match v {
+                        Value::Null => form.append_pair(k, ""),
+                        Value::String(s) => form.append_pair(k, s),
+                        v => form.append_pair(k, &v.to_string()),
+                    };
+                }
+                Ok((form.finish().into_bytes(), content_type))
+            }
+        }
+    }
+}
+
+/// Single pass over the template, so values can't smuggle in placeholders.
+/// Unknown placeholders are left as they are.
+fn fill_template(template: &str, values: &HashMap<String, String>) -> String {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after.find('}') {
+            Some(end) if values.contains_key(&after[..end]) => {
+                out.push_str(&values[&after[..end]]);
+                rest = &after[end + 1..];
+            }
+            _ => {
+                out.push('{');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Fills the url template through url::Url, so each value is encoded as the
+/// path segment or query pair it sits in and can't add segments or parameters.
+/// A value that would make a path segment `.` or `..` is refused
+fn fill_url(template: &str, values: &HashMap<String, String>) -> anyhow::Result<Url> {
+    let template = template.split('#').next().unwrap_or_default();
+    let (base, query) = match template.split_once('?') {
+        Some((base, query)) => (base, Some(query)),
+        None => (template, None),
+    };
+    let bad_url = || anyhow::anyhow!("bad url template {}", template);
+    let authority = base.find("://").ok_or_else(bad_url)? + 3;
+    let path_start = base[authority..]
+        .find('/')
+        .map(|i| i + authority)
+        .unwrap_or(base.len());
+    let mut url = Url::parse(&base[..path_start])?;
+    {
+        let mut segments = url.path_segments_mut().map_err(|_| bad_url())?;
+        segments.clear();
+        for segment in base[path_start..].split('/').skip(1) {
+            let filled = fill_template(segment, values);
+            // url resolves these instead of encoding them
+            if filled != segment && (filled == "." || filled == "..") {
+                return Err(anyhow::anyhow!("{} can't be a path segment", filled));
+            }
+            segments.push(&filled);
+        }
+    }
+    if let Some(query) = query {
+        let mut pairs = url.query_pairs_mut();
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            pairs.append_pair(&fill_template(name, values), &fill_template(value, values));
+        }
+    }
+    Ok(url)
+}
+
+/// Fills every string in a JSON template. A string that is a single
+/// placeholder for an argument takes the argument's value, keeping its type.
+fn fill_json(
+    template: &Value,
+    values: &HashMap<String, String>,
+    raw: &HashMap<String, Value>,
+) -> Value {
+    match template {
+        Value::String(s) => {
+            let whole = s.strip_prefix('{').and_then(|s| s.strip_suffix('}'));
+            match whole.and_then(|name| raw.get(name)) {
+                Some(v) => v.clone(),
+                None => Value::String(fill_template(s, values)),
+            }
+        }
+        Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|item| fill_json(item, values, raw))
+                .collect(),
+        ),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(k, v)| (k.clone(), fill_json(v, values, raw)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+/// Text for url and header placeholders; arrays and objects go in as JSON
+fn argument_string(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    const HOSTILE: &str = "a&b=c#d?e f/../{other}";
+
+    #[test]
+    fn query_values_stay_in_their_pair() {
+        let values = values(&[("query", HOSTILE), ("other", "x")]);
+        let url = fill_url("https://example.com/v1/search?q={query}&k=1", &values).unwrap();
+        let pairs = url.query_pairs().into_owned().collect::<Vec<_>>();
+        assert_eq!(
+            pairs,
+            vec![
+                ("q".to_string(), HOSTILE.to_string()),
+                ("k".to_string(), "1".to_string()),
+            ]
+        );
+        assert_eq!(url.fragment(), None);
+        assert_eq!(url.path(), "/v1/search");
+    }
+
+    #[test]
+    fn path_values_stay_in_their_segment() {
+        let values = values(&[("symbol", HOSTILE), ("other", "x")]);
+        let url = fill_url("https://example.com/v1/{symbol}/quote", &values).unwrap();
+        let segments = url.path_segments().unwrap().collect::<Vec<_>>();
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[0], "v1");
+        assert_eq!(segments[2], "quote");
+        assert_eq!(segments[1], "a&b=c%23d%3Fe%20f%2F..%2F%7Bother%7D");
+        assert_eq!(url.query(), None);
+        assert_eq!(url.fragment(), None);
+        assert_eq!(url.host_str(), Some("example.com"));
+    }
+
+    #[test]
+    fn dot_segments_are_refused() {
+        for dots in [".", ".."] {
+            let values = values(&[("symbol", dots)]);
+            assert!(fill_url("https://example.com/v1/{symbol}/quote", &values).is_err());
+        }
+    }
+
+    #[test]
+    fn placeholders_in_values_are_not_expanded() {
+        let values = values(&[("query", "{secret}"), ("secret", "s3cret")]);
+        assert_eq!(fill_template("q={query}", &values), "q={secret}");
+        let url = fill_url("https://example.com/{query}?q={query}", &values).unwrap();
+        assert!(!url.as_str().contains("s3cret"));
+        assert_eq!(url.query_pairs().next().unwrap().1, "{secret}");
+    }
+
+    #[test]
+    fn unknown_placeholders_are_left_alone() {
+        let values = values(&[("query", "x")]);
+        assert_eq!(fill_template("{query}{other}{", &values), "x{other}{");
+    }
+
+    #[test]
+    fn json_strings_take_values_verbatim() {
+        let values = values(&[("query", HOSTILE), ("other", "x")]);
+        let raw = HashMap::from([
+            ("query".to_string(), json!(HOSTILE)),
+            ("limit".to_string(), json!(10)),
+        ]);
+        let template = json!({"q": "{query}", "text": "find {query}", "limit": "{limit}"});
+        let filled = fill_json(&template, &values, &raw);
+        assert_eq!(
+            filled,
+            json!({"q": HOSTILE, "text": format!("find {}", HOSTILE), "limit": 10})
+        );
+    }
+
+    #[test]
+    fn only_drive_files_are_deleted() {
+        let drive = "/hpn:sortugdev.os/providers";
+        let def = |path: &str| {
+            let mut def =
+                serde_json::from_value::<ProviderDef>(json!({"name": "x", "url": "https://x.io"}))
+                    .unwrap();
+            def.path = Some(path.to_string());
+            def
+        };
+        let defs = HashMap::from([
+            ("added".to_string(), def(&format!("{}/added.toml", drive))),
+            (
+                "shipped".to_string(),
+                def("/hpn:sortugdev.os/pkg/providers/shipped.json"),
+            ),
+        ]);
+        assert_eq!(
+            added_path(drive, &defs, "added"),
+            Some(format!("{}/added.toml", drive))
+        );
+        assert_eq!(added_path(drive, &defs, "shipped"), None);
+        assert_eq!(added_path(drive, &defs, "missing"), None);
+    }
+}
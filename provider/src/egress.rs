@@ -0,0 +1,182 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use hyperware_process_lib::logging::error;
+use url::{Host, Url};
+
+use crate::definitions::ProviderDef;
+use crate::structs::*;
+
+// outbound calls only go to hosts a provider's definition allows, and never
+// to loopback, private or link-local addresses unless it opts in with allow_private.
+// names are checked as written: we can't see what they resolve to, which is
+// what the allowlist is for. IP literals are checked in every form url accepts,
+// including IPv6 addresses that carry an IPv4 one.
+// http-client may follow redirects itself, and OutgoingHttpRequest has no way
+// to turn that off or to see where they led, so only the first url is checked:
+// only allow hosts trusted not to redirect to internal addresses.
+
+/// Refuses urls the provider may not reach, logging each refusal as a security event
+pub fn check(def: &ProviderDef, url: &Url) -> Result<(), ProviderError> {
+    match blocked_reason(def, url) {
+        None => Ok(()),
+        Some(reason) => {
+            error!(
+                "security event: blocked call from {} to {}: {}",
+                def.name,
+                url.host_str().unwrap_or_default(),
+                reason
+            );
+            Err(ProviderError::new(
+                ProviderErrorCode::Forbidden,
+                "upstream host not allowed",
+            ))
+        }
+    }
+}
+
+fn blocked_reason(def: &ProviderDef, url: &Url) -> Option<String> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Some(format!("scheme {} not allowed", url.scheme()));
+    }
+    let host = match url.host() {
+        Some(host) => host,
+        None => return Some("no host".to_string()),
+    };
+    let name = url.host_str().unwrap_or_default().to_lowercase();
+    let allowed = def.allowed_hosts();
+    if !allowed.iter().any(|pattern| host_matches(pattern, &name)) {
+        return Some(format!("not in allowlist {:?}", allowed));
+    }
+    if def.allow_private {
+        return None;
+    }
+    let private = match host {
+        Host::Domain(domain) => {
+            let domain = domain.to_lowercase();
+            domain == "localhost" || domain.ends_with(".localhost")
+        }
+        Host::Ipv4(ip) => private_v4(&ip),
+        Host::Ipv6(ip) => private_v6(&ip),
+    };
+    private.then(|| "private address".to_string())
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host.ends_with(&format!(".{}", domain)),
+        None => pattern == host,
+    }
+}
+
+fn private_v4(ip: &Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        // "this network", 0.0.0.0/8
+        || a == 0
+        // shared address space, 100.64.0.0/10
+        || (a == 100 && (64..128).contains(&b))
+        // benchmarking, 198.18.0.0/15
+        || (a == 198 && (18..20).contains(&b))
+        // reserved, 240.0.0.0/4
+        || a >= 240
+}
+
+fn private_v6(ip: &Ipv6Addr) -> bool {
+    if let Some(v4) = embedded_v4(ip) {
+        return private_v4(&v4);
+    }
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // unique local, fc00::/7
+        || (first & 0xfe00) == 0xfc00
+        // link-local, fe80::/10
+        || (first & 0xffc0) == 0xfe80
+        // site-local, fec0::/10
+        || (first & 0xffc0) == 0xfec0
+}
+
+/// The IPv4 address inside mapped (::ffff:a.b.c.d), compatible (::a.b.c.d),
+/// NAT64 (64:ff9b::a.b.c.d) and 6to4 (2002:ab:cd::) addresses
+fn embedded_v4(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    let s = ip.segments();
+    let tail = |hi: u16, lo: u16| Ipv4Addr::from(((hi as u32) << 16) | lo as u32);
+    match s {
+        [0, 0, 0, 0, 0, 0xffff, hi, lo] => Some(tail(hi, lo)),
+        // :: and ::1 are handled as IPv6
+        [0, 0, 0, 0, 0, 0, hi, lo] if hi != 0 => Some(tail(hi, lo)),
+        [0x64, 0xff9b, 0, 0, 0, 0, hi, lo] => Some(tail(hi, lo)),
+        [0x2002, hi, lo, ..] => Some(tail(hi, lo)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn def(allow_private: bool) -> ProviderDef {
+        let mut def = serde_json::from_value::<ProviderDef>(serde_json::json!({
+            "name": "example",
+            "url": "https://api.example.com/v1",
+            "allowed_hosts": ["api.example.com", "*"],
+        }))
+        .unwrap();
+        def.allow_private = allow_private;
+        def
+    }
+
+    fn blocked(url: &str) -> bool {
+        let mut def = def(false);
+        let host = Url::parse(url).unwrap().host_str().unwrap().to_string();
+        def.allowed_hosts = vec![host];
+        blocked_reason(&def, &Url::parse(url).unwrap()).is_some()
+    }
+
+    #[test]
+    fn private_ip_literals_are_blocked() {
+        for url in [
+            "http://127.0.0.1/",
+            "http://2130706433/",
+            "http://0x7f.1/",
+            "http://10.0.0.1/",
+            "http://172.16.3.4/",
+            "http://192.168.1.1/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://0.0.0.0/",
+            "http://100.64.0.1/",
+            "http://[::1]/",
+            "http://[::ffff:169.254.169.254]/",
+            "http://[::169.254.169.254]/",
+            "http://[64:ff9b::a9fe:a9fe]/",
+            "http://[2002:a9fe:a9fe::]/",
+            "http://[fe80::1]/",
+            "http://[fd00::1]/",
+            "http://localhost/",
+        ] {
+            assert!(blocked(url), "{} wasn't blocked", url);
+        }
+    }
+
+    #[test]
+    fn public_ip_literals_are_let_through() {
+        for url in ["http://1.1.1.1/", "http://[2606:4700:4700::1111]/"] {
+            assert!(!blocked(url), "{} was blocked", url);
+        }
+    }
+
+    #[test]
+    fn allow_private_opts_in() {
+        let mut def = def(true);
+        def.allowed_hosts = vec!["127.0.0.1".to_string()];
+        let url = Url::parse("http://127.0.0.1:8080/").unwrap();
+        assert!(blocked_reason(&def, &url).is_none());
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+
+use hyperware_process_lib::http::server::{
+    send_response, HttpBindingConfig, HttpServer, HttpServerRequest, IncomingHttpRequest,
+};
+use hyperware_process_lib::http::{Method, StatusCode};
+use hyperware_process_lib::logging::info;
+use hyperware_process_lib::sqlite::Sqlite;
+use hyperware_process_lib::{last_blob, Address};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::audit;
+use crate::db as dbm;
+use crate::definitions;
+use crate::structs::*;
+use crate::usage;
+
+// the operator's API, and the page in admin.html that drives it.
+// everything is bound with the default config: only for whoever is logged into our node.
+// secret values go in and never come back out, only their names and fingerprints do.
+
+pub fn init_http() -> anyhow::Result<HttpServer> {
+    let mut http_server = HttpServer::new(5);
+    let http_config = HttpBindingConfig::default();
+
+    http_server.bind_http_path("/api/usage", http_config.clone())?;
+    http_server.bind_http_path("/api/audit", http_config.clone())?;
+    http_server.bind_http_path("/api/secrets", http_config.clone())?;
+    http_server.bind_http_path("/api/secrets/:name", http_config.clone())?;
+    http_server.bind_http_path("/api/keys", http_config.clone())?;
+    http_server.bind_http_path("/api/keys/:wallet", http_config.clone())?;
+    http_server.bind_http_path("/api/definitions", http_config.clone())?;
+    http_server.bind_http_path("/api/definitions/:name", http_config)?;
+    http_server.bind_http_static_path(
+        "/",
+        true,
+        false,
+        Some("text/html".to_string()),
+        include_bytes!("admin.html").to_vec(),
+    )?;
+
+    Ok(http_server)
+}
+
+/// an incoming key handed out by the operator rather than bought
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct KeyGrant {
+    wallet: String,
+    /// generated when left out
+    api_key: Option<String>,
+    node: Option<String>,
+    #[serde(default)]
+    providers: Vec<String>,
+    /// None for unlimited
+    credits: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct SecretValue {
+    value: String,
+}
+
+pub fn handle_frontend(
+    our: &Address,
+    body: &[u8],
+    state: &mut State,
+    db: &Sqlite,
+) -> anyhow::Result<()> {
+    let server_request: HttpServerRequest = serde_json::from_slice(body)?;
+    if let HttpServerRequest::Http(req) = server_request {
+        let prefix = format!("{}:{}/api", our.process(), our.package_id());
+        let path = req.bound_path(Some(&prefix)).to_string();
+        let method = req.method()?;
+        info!("request path: {} {}", method, path);
+        if let Err(e) = route(our, &req, method, &path, state, db) {
+            let e = ProviderError::from(e);
+            send_json_response(status_for(&e.code), &e)?;
+        }
+    }
+    Ok(())
+}
+
+fn route(
+    our: &Address,
+    req: &IncomingHttpRequest,
+    method: Method,
+    path: &str,
+    state: &mut State,
+    db: &Sqlite,
+) -> anyhow::Result<()> {
+    let params = req.query_params();
+    let url_param = |name: &str| {
+        req.url_params()
+            .get(name)
+            .cloned()
+            .ok_or(bad_request(format!("missing {}", name)))
+    };
+    match (method, path) {
+        // /usage?caller=&provider=
+        (Method::GET, "/usage") => {
+            let caller = params.get("caller").map(|c| c.as_str());
+            let provider = params.get("provider").map(|p| p.as_str());
+            let rows = usage::get(db, caller, provider)?;
+            send_json_response(StatusCode::OK, &usage::nested(rows))
+        }
+        // /audit?caller=&provider=&status=&since=&until=&limit=
+        (Method::GET, "/audit") => {
+            let filter = audit::AuditFilter::from_params(params).map_err(bad_request)?;
+            let rows = dbm::get_audit(db, &filter)?;
+            send_json_response(StatusCode::OK, &json!(rows))
+        }
+        (Method::GET, "/secrets") => {
+            let secrets = state
+                .secrets
+                .fingerprints()
+                .into_iter()
+                .map(|(name, fingerprint)| json!({"name": name, "fingerprint": fingerprint}))
+                .collect::<Vec<_>>();
+            send_json_response(StatusCode::OK, &secrets)
+        }
+        // body: {"value": "..."}
+        (Method::PUT, "/secrets/:name") => {
+            let name = url_param("name")?;
+            let secret = parse_body::<SecretValue>()?;
+            state.secrets.insert(name, secret.value);
+            state.secrets.save(our)?;
+            send_json_response(StatusCode::OK, &json!({}))
+        }
+        (Method::DELETE, "/secrets/:name") => {
+            let name = url_param("name")?;
+            state
+                .secrets
+                .remove(&name)
+                .ok_or(not_found(format!("no secret {}", name)))?;
+            state.secrets.save(our)?;
+            send_json_response(StatusCode::OK, &json!({}))
+        }
+        (Method::GET, "/keys") => {
+            let mut users = state.in_keys.values().collect::<Vec<_>>();
+            users.sort_by(|a, b| a.wallet.cmp(&b.wallet));
+            send_json_response(StatusCode::OK, &users)
+        }
+        // body: a KeyGrant, answered with the stored user and its key
+        (Method::POST, "/keys") => {
+            let grant = parse_body::<KeyGrant>()?;
+            let user = User {
+                wallet: grant.wallet.clone(),
+                tx_hash: String::new(),
+                api_key: grant
+                    .api_key
+                    .unwrap_or(hex::encode(rand::random::<[u8; 32]>())),
+                node: grant.node,
+                providers: grant.providers,
+                credits: grant.credits,
+                paid_credits: HashMap::new(),
+            };
+            state.in_keys.insert(grant.wallet, user.clone());
+            state.save();
+            send_json_response(StatusCode::OK, &user)
+        }
+        (Method::DELETE, "/keys/:wallet") => {
+            let wallet = url_param("wallet")?;
+            state
+                .in_keys
+                .remove(&wallet)
+                .ok_or(not_found(format!("no key for {}", wallet)))?;
+            state.save();
+            send_json_response(StatusCode::OK, &json!({}))
+        }
+        (Method::GET, "/definitions") => {
+            let mut defs = state.definitions.values().collect::<Vec<_>>();
+            defs.sort_by(|a, b| a.name.cmp(&b.name));
+            send_json_response(StatusCode::OK, &defs)
+        }
+        // body: a provider definition, same as add-provider
+        (Method::POST, "/definitions") => {
+            let blob = last_blob().ok_or(bad_request("no body"))?;
+            let json = std::str::from_utf8(blob.bytes()).map_err(|_| bad_request("not UTF-8"))?;
+            let def =
+                definitions::save_definition(our, &state.definitions, json).map_err(bad_request)?;
+            info!("saved provider definition {}", def.name);
+            state.definitions = definitions::load_definitions(our);
+            send_json_response(StatusCode::OK, &def)
+        }
+        (Method::DELETE, "/definitions/:name") => {
+            let name = url_param("name")?;
+            definitions::delete_definition(our, &state.definitions, &name)
+                .map_err(|_| not_found(format!("no added definition {}", name)))?;
+            state.definitions = definitions::load_definitions(our);
+            send_json_response(StatusCode::OK, &json!({}))
+        }
+        (method, path) => Err(not_found(format!("no such path: {} {}", method, path)).into()),
+    }
+}
+
+fn parse_body<T: serde::de::DeserializeOwned>() -> Result<T, ProviderError> {
+    let blob = last_blob().ok_or(bad_request("no body"))?;
+    serde_json::from_slice::<T>(blob.bytes()).map_err(bad_request)
+}
+
+fn bad_request(message: impl ToString) -> ProviderError {
+    ProviderError::new(ProviderErrorCode::BadRequest, message)
+}
+
+fn not_found(message: impl ToString) -> ProviderError {
+    ProviderError::new(ProviderErrorCode::NotFound, message)
+}
+
+fn status_for(code: &ProviderErrorCode) -> StatusCode {
+    match code {
+        ProviderErrorCode::BadRequest => StatusCode::BAD_REQUEST,
+        ProviderErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+        ProviderErrorCode::PaymentInvalid => StatusCode::PAYMENT_REQUIRED,
+        ProviderErrorCode::Forbidden => StatusCode::FORBIDDEN,
+        ProviderErrorCode::NotFound => StatusCode::NOT_FOUND,
+        ProviderErrorCode::QuotaExceeded | ProviderErrorCode::RateLimited => {
+            StatusCode::TOO_MANY_REQUESTS
+        }
+        ProviderErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        ProviderErrorCode::Upstream
+        | ProviderErrorCode::UpstreamDns
+        | ProviderErrorCode::UpstreamClient
+        | ProviderErrorCode::UpstreamServer => StatusCode::BAD_GATEWAY,
+        ProviderErrorCode::UpstreamTimeout => StatusCode::GATEWAY_TIMEOUT,
+        ProviderErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn send_json_response<T: serde::Serialize>(status: StatusCode, data: &T) -> anyhow::Result<()> {
+    let json_data = serde_json::to_vec(data)?;
+    send_response(
+        status,
+        Some(HashMap::from([(
+            String::from("Content-Type"),
+            String::from("application/json"),
+        )])),
+        json_data,
+    );
+    Ok(())
+}
@@ -1,13 +1,18 @@
 use core::str;
 use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Instant;
 
-use hyperware_process_lib::http::client::send_request_await_response;
-use hyperware_process_lib::http::Method;
+use hyperware_process_lib::http::client::{
+    HttpClientAction, HttpClientError, HttpClientResponse, HttpResponse, OutgoingHttpRequest,
+};
 use hyperware_process_lib::logging::{error, info, init_logging, Level};
-use hyperware_process_lib::{await_message, call_init, kiprintln, Address, Message, Response};
+use hyperware_process_lib::sqlite::Sqlite;
+use hyperware_process_lib::{
+    await_message, call_init, Address, LazyLoadBlob, Message, Request, Response,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use url::Url;
 
 use hyperware_process_lib::vfs::{create_drive, open_dir, open_file};
 wit_bindgen::generate!({
@@ -16,13 +21,37 @@ wit_bindgen::generate!({
     generate_unused_types: true,
     additional_derives: [serde::Deserialize, serde::Serialize, process_macros::SerdeJsonInto],
 });
+mod audit;
+#[path = "../../shared/audit_common.rs"]
+mod audit_common;
+mod auth;
+mod cache;
+mod db;
+mod definitions;
+mod egress;
+mod http_handlers;
+mod payments;
+mod publish;
+mod ratelimit;
+mod retry;
+mod schema;
+mod secrets;
 mod structs;
+mod usage;
 use structs::*;
 
+// the variant names are on the wire, indexers send {"MCP": ...}
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Debug, Deserialize, Serialize)]
 enum ProviderRequest {
     API(ApiKeyHandling),
     MCP(MCPRequest),
+    /// trade a payment tx for a key with credits
+    Pay(payments::PaymentClaim),
+    /// JSON Schema of a provider's arguments
+    Schema(String),
+    /// health probe from an indexer, answered with the provider names we serve
+    Ping,
 }
 #[derive(Clone, Debug, Deserialize, Serialize)]
 enum ApiKeyHandling {
@@ -33,24 +62,111 @@ enum ApiKeyHandling {
 struct MCPRequest {
     provider_name: String,
     arguments: HashMap<String, Value>,
+    /// key issued by us, required for key-gated providers
+    #[serde(default)]
+    api_key: Option<String>,
+    /// ms the caller will wait for an answer; retries stop short of it
+    #[serde(default)]
+    deadline_ms: Option<u64>,
 }
-fn call_json_api(url: &str, headers: Option<HashMap<String, String>>) -> anyhow::Result<Vec<u8>> {
-    info!("calling {}", url);
-    let mut hs = HashMap::new();
-    hs.insert("Content-type".to_string(), "application/json".to_string());
-    if let Some(heds) = headers {
-        hs.extend(heds);
-    }
-    let url = Url::parse(&url)?;
-    let res = send_request_await_response(Method::GET, url, Some(hs), 60, vec![]);
-    match res {
-        Err(e) => {
-            let error_body = serde_json::to_vec(&e.to_string())?;
-            Ok(error_body)
+fn call_json_api(
+    call: definitions::PreparedCall,
+    timeout_s: u64,
+) -> anyhow::Result<ProviderResponse> {
+    let started = Instant::now();
+    // the query string can hold our keys, keep it out of the logs
+    info!(
+        "calling {} {}",
+        call.method,
+        &call.url[..url::Position::AfterPath]
+    );
+    let res = send_upstream(call, timeout_s);
+    let latency_ms = started.elapsed().as_millis() as u64;
+    let (res, bytes) = res.map_err(|e| classify_transport_error(e, latency_ms))?;
+    let latency_ms = started.elapsed().as_millis() as u64;
+    let headers = res
+        .headers
+        .into_iter()
+        .map(|(name, value)| (name.to_lowercase(), value))
+        .filter(|(name, _)| FORWARDED_HEADERS.contains(&name.as_str()))
+        .collect();
+    let response = ProviderResponse::new(res.status, headers, bytes, latency_ms);
+    Ok(response.into_result()?)
+}
+/// why an upstream call got no HTTP response back
+#[derive(Debug)]
+enum TransportError {
+    /// http-client didn't answer within the timeout
+    Timeout,
+    /// http-client couldn't be reached
+    Offline,
+    Client(HttpClientError),
+}
+/// Sends the call through http-client, keeping timeouts apart from its own errors
+fn send_upstream(
+    call: definitions::PreparedCall,
+    timeout_s: u64,
+) -> Result<(HttpResponse, Vec<u8>), TransportError> {
+    let action = HttpClientAction::Http(OutgoingHttpRequest {
+        method: call.method.to_string(),
+        version: None,
+        url: call.url.to_string(),
+        headers: call.headers,
+    });
+    let body = serde_json::to_vec(&action)
+        .map_err(|_| TransportError::Client(HttpClientError::MalformedRequest))?;
+    let res = Request::to(("our", "http-client", "distro", "sys"))
+        .body(body)
+        .blob_bytes(call.body)
+        .send_and_await_response(timeout_s)
+        .map_err(|_| TransportError::Client(HttpClientError::MalformedRequest))?;
+    let message = match res {
+        Ok(message) => message,
+        Err(e) if e.kind().is_timeout() => return Err(TransportError::Timeout),
+        Err(_) => return Err(TransportError::Offline),
+    };
+    match serde_json::from_slice::<Result<HttpClientResponse, HttpClientError>>(message.body()) {
+        Ok(Ok(HttpClientResponse::Http(res))) => {
+            let bytes = message.blob().map(|b| b.bytes).unwrap_or_default();
+            Ok((res, bytes))
         }
-        Ok(r) => Ok(r.body().to_vec()),
+        Ok(Err(e)) => Err(TransportError::Client(e)),
+        _ => Err(TransportError::Client(
+            HttpClientError::ExecuteRequestFailed(
+                "unexpected response from http-client".to_string(),
+            ),
+        )),
     }
 }
+/// Only the kind of failure goes back, the error text can carry the url and with it our key.
+/// Failures to reach the upstream are worth retrying; a request http-client
+/// wouldn't even build is our fault and isn't
+fn classify_transport_error(error: TransportError, latency_ms: u64) -> ProviderError {
+    let (code, message) = match error {
+        TransportError::Timeout => (ProviderErrorCode::UpstreamTimeout, "upstream timed out"),
+        TransportError::Offline => (ProviderErrorCode::Upstream, "upstream request failed"),
+        // reqwest's text is all that tells a lookup failure apart; it only labels the error
+        TransportError::Client(HttpClientError::ExecuteRequestFailed(detail))
+            if detail.to_lowercase().contains("dns") =>
+        {
+            (
+                ProviderErrorCode::UpstreamDns,
+                "upstream host didn't resolve",
+            )
+        }
+        TransportError::Client(HttpClientError::ExecuteRequestFailed(_)) => {
+            (ProviderErrorCode::Upstream, "upstream request failed")
+        }
+        TransportError::Client(e) => {
+            error!("couldn't build upstream request: {:?}", e);
+            (
+                ProviderErrorCode::Internal,
+                "couldn't build the upstream request",
+            )
+        }
+    };
+    ProviderError::new(code, message).with_details(json!({ "latency_ms": latency_ms }))
+}
 
 fn catpic_list(our: &Address) -> Value {
     let drive_path = create_drive(our.package_id(), "pkg", None).unwrap();
@@ -66,6 +182,7 @@ fn catpic_list(our: &Address) -> Value {
     json!(files)
 }
 fn get_catpic(our: &Address, file_name: &str) -> anyhow::Result<Vec<u8>> {
+    check_catpic_name(file_name)?;
     let drive_path = create_drive(our.package_id(), "pkg", None).unwrap();
     info!("{}", drive_path);
     let file_path = format!("{}/pics/{}", drive_path, file_name);
@@ -73,6 +190,33 @@ fn get_catpic(our: &Address, file_name: &str) -> anyhow::Result<Vec<u8>> {
     let file_bytes = file.read()?;
     Ok(file_bytes)
 }
+/// Picture names are plain file names in pics/, never paths out of it
+fn check_catpic_name(file_name: &str) -> Result<(), ProviderError> {
+    let path_like = file_name.is_empty()
+        || file_name.starts_with('.')
+        || file_name.contains("..")
+        || file_name
+            .chars()
+            .any(|c| c == '/' || c == '\\' || c.is_control());
+    if path_like {
+        return Err(ProviderError::new(
+            ProviderErrorCode::BadRequest,
+            "bad picture name",
+        ));
+    }
+    Ok(())
+}
+fn mime_from_extension(file_name: &str) -> &'static str {
+    let extension = file_name.rsplit('.').next().unwrap_or_default();
+    match extension.to_lowercase().as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        _ => "application/octet-stream",
+    }
+}
 fn handle_api_request(req: ApiKeyHandling, state: &mut State) -> anyhow::Result<()> {
     match req {
         ApiKeyHandling::Set(u) => {
@@ -83,119 +227,185 @@ fn handle_api_request(req: ApiKeyHandling, state: &mut State) -> anyhow::Result<
             state.in_keys.remove(&s);
         }
     }
+    state.save();
     Ok(())
 }
-fn handle_mcp_request(our: &Address, req: MCPRequest, state: &mut State) -> anyhow::Result<()> {
+fn handle_mcp_request(
+    our: &Address,
+    source: &Address,
+    req: MCPRequest,
+    state: &mut State,
+    db: &Sqlite,
+) -> anyhow::Result<()> {
     // kiprintln!("provider: {:#?}", req);
-    match req.provider_name.as_str() {
-        "weatherapi" => {
-            let api_key = state
-                .out_keys
-                .get("WEATHER_API_KEY")
-                .ok_or(anyhow::anyhow!("no outgoing API KEY"))?;
-            let query_value = req
-                .arguments
-                .get("query")
-                .ok_or(anyhow::anyhow!("bad arguments sent"))?;
-            let query = query_value
-                .as_str()
-                .ok_or(anyhow::anyhow!("bad query argument sent"))?;
-            let url = format!(
-                "https://api.weatherapi.com/v1/current.json?key={}&q={}",
-                api_key, query
-            );
-            let res_body = call_json_api(&url, None)?;
-            Response::new().body(res_body).send()?;
-        }
-        "Finnhub API" => {
-            let api_key = state
-                .out_keys
-                .get("FINNHUB_API_KEY")
-                .ok_or(anyhow::anyhow!("no outgoing API KEY"))?;
-            let symbol_value = req
-                .arguments
-                .get("symbol")
-                .ok_or(anyhow::anyhow!("bad arguments sent"))?;
-            let symbol = symbol_value
-                .as_str()
-                .ok_or(anyhow::anyhow!("bad symbol argument sent"))?;
-            let url = format!(
-                "https://finnhub.io/api/v1/quote?symbol={}&token={}",
-                symbol, api_key
-            );
-            let res_body = call_json_api(&url, None)?;
-            Response::new().body(res_body).send()?;
-        }
-        "dune" => {
-            let api_key = state
-                .out_keys
-                .get("DUNE_API_KEY")
-                .ok_or(anyhow::anyhow!("no outgoing API KEY"))?;
-            let contract = req
-                .arguments
-                .get("contract")
-                .ok_or(anyhow::anyhow!("bad arguments sent"))?
-                .as_str()
-                .ok_or(anyhow::anyhow!("bad arguments sent"))?;
-            let chain_idv = req
-                .arguments
-                .get("chainId")
-                .ok_or(anyhow::anyhow!("bad arguments sent"))?;
-            let chain_id = match chain_idv {
-                Value::Number(n) => n.to_string(),
-                Value::String(s) => s.to_string(),
-                _ => return Err(anyhow::anyhow!("bad arguments sent")),
-            };
-            let url = format!(
-                "https://api.dune.com/api/echo/beta/tokens/evm/{}?chain_ids={}",
-                contract, chain_id
-            );
-            let mut headers = HashMap::new();
-            headers.insert("X-Dune-Api-Key".to_string(), api_key.to_string());
-            let res_body = call_json_api(&url, Some(headers))?;
-            Response::new().body(res_body).send()?;
-        }
-        "Catpics" => {
-            let query_value = req
-                .arguments
-                .get("query")
-                .ok_or(anyhow::anyhow!("bad arguments sent"))?;
-            match query_value.to_owned() {
-                Value::String(s) => {
-                    if s.as_str() == "list" {
-                        let catpic_list = catpic_list(our);
-                        Response::new()
-                            .body(serde_json::to_vec(&catpic_list)?)
-                            .send()?;
-                    } else {
-                        return Err(anyhow::anyhow!("bad arguments sent"));
-                    }
-                }
-                Value::Object(obj) => {
-                    let file_name = obj
-                        .get("file")
-                        .ok_or(anyhow::anyhow!("bad arguments sent"))?
-                        .as_str()
-                        .ok_or(anyhow::anyhow!("bad arguments sent"))?;
-                    let catpic = get_catpic(our, file_name);
-                    match catpic {
-                        Ok(bytes) => {
-                            Response::new().body(bytes).send()?;
+    let started = Instant::now();
+    let caller = usage::caller_id(source, req.api_key.as_deref(), state);
+    let res = serve_mcp_request(our, source, &req, &caller, state, db);
+    let latency_ms = started.elapsed().as_millis() as u64;
+    audit::record(
+        db,
+        &state.audit_key,
+        &caller,
+        &req.provider_name,
+        &req.arguments,
+        &res,
+        latency_ms,
+    );
+    let res = res?;
+    let mut response = Response::new().body(serde_json::to_vec(&res)?);
+    // binary and large bodies go as a lazy-load blob tagged with their type
+    if let Some(blob) = res.blob {
+        response = response.blob(LazyLoadBlob::new(Some(res.mime), blob));
+    }
+    response.send()?;
+    Ok(())
+}
+/// Everything between receiving a call and answering it
+fn serve_mcp_request(
+    our: &Address,
+    source: &Address,
+    req: &MCPRequest,
+    caller: &str,
+    state: &mut State,
+    db: &Sqlite,
+) -> anyhow::Result<ProviderResponse> {
+    if source.node() != our.node() {
+        ratelimit::take(
+            state,
+            source.node(),
+            &req.provider_name,
+            ratelimit::now_ms(),
+        )?;
+    }
+    auth::authorize(
+        our,
+        source,
+        &req.provider_name,
+        req.api_key.as_deref(),
+        state,
+    )?;
+    usage::check_quota(db, state, caller, &req.provider_name)?;
+    let res = match cache::lookup(db, state, &req.provider_name, &req.arguments) {
+        Some(hit) => Ok(hit),
+        None => {
+            let res = call_provider(our, req, state);
+            if let Ok(r) = &res {
+                cache::store(db, state, &req.provider_name, &req.arguments, r);
+            }
+            res
+        }
+    };
+    let outcome = usage::Outcome::of(&res);
+    usage::record(db, state, caller, &req.provider_name, outcome);
+    res
+}
+fn call_provider(
+    our: &Address,
+    req: &MCPRequest,
+    state: &mut State,
+) -> anyhow::Result<ProviderResponse> {
+    let schema = schema_for(state, &req.provider_name).ok_or(ProviderError::new(
+        ProviderErrorCode::NotFound,
+        "no such provider here",
+    ))?;
+    let arguments = serde_json::to_value(&req.arguments)?;
+    let errors = schema::validate(&schema, &arguments);
+    if !errors.is_empty() {
+        let e = ProviderError::new(ProviderErrorCode::BadRequest, "bad arguments sent");
+        return Err(e.with_details(json!(errors)).into());
+    }
+    // Catpics serves files from our own drive, everything else is an upstream API
+    if req.provider_name.as_str() == "Catpics" {
+        let started = Instant::now();
+        let (bytes, mime) = handle_catpics(our, req)?;
+        let latency_ms = started.elapsed().as_millis() as u64;
+        let headers = HashMap::from([("content-type".to_string(), mime)]);
+        return Ok(ProviderResponse::new(200, headers, bytes, latency_ms));
+    }
+    let def = state
+        .definitions
+        .get(&req.provider_name)
+        .ok_or(anyhow::anyhow!("no such provider here"))?;
+    let call = def.prepare(&req.arguments, &state.secrets)?;
+    egress::check(def, &call.url)?;
+    retry::call(&mut state.breakers, def, call, req.deadline_ms)
+}
+/// Every provider name serve_mcp_request accepts, sorted
+fn served_names(definitions: &HashMap<String, definitions::ProviderDef>) -> Vec<&str> {
+    let mut names = definitions.keys().map(|n| n.as_str()).collect::<Vec<_>>();
+    if !definitions.contains_key("Catpics") {
+        names.push("Catpics");
+    }
+    names.sort();
+    names
+}
+fn schema_for(state: &State, provider_name: &str) -> Option<Value> {
+    if provider_name == "Catpics" {
+        return Some(json!({
+            "type": "object",
+            "properties": {
+                "query": {
+                    "anyOf": [
+                        {"const": "list"},
+                        {
+                            "type": "object",
+                            "properties": {"file": {"type": "string"}},
+                            "required": ["file"]
                         }
-                        Err(e) => return Err(e),
-                    }
+                    ]
                 }
-                _ => return Err(anyhow::anyhow!("bad arguments sent")),
+            },
+            "required": ["query"]
+        }));
+    }
+    state
+        .definitions
+        .get(provider_name)
+        .map(|def| def.input_schema())
+}
+/// The picture list as JSON or a picture's bytes, with their MIME type
+fn handle_catpics(our: &Address, req: &MCPRequest) -> anyhow::Result<(Vec<u8>, String)> {
+    let query_value = req
+        .arguments
+        .get("query")
+        .ok_or(anyhow::anyhow!("bad arguments sent"))?;
+    info!("{:#?}", req.arguments);
+    match query_value.to_owned() {
+        Value::String(s) => {
+            if s.as_str() == "list" {
+                let catpic_list = catpic_list(our);
+                Ok((
+                    serde_json::to_vec(&catpic_list)?,
+                    "application/json".to_string(),
+                ))
+            } else {
+                Err(anyhow::anyhow!("bad arguments sent"))
             }
-            info!("{:#?}", req.arguments);
-            ()
         }
-        _ => return Err(anyhow::anyhow!("no such provider here")),
-    };
-    Ok(())
+        Value::Object(obj) => {
+            let file_name = obj
+                .get("file")
+                .ok_or(anyhow::anyhow!("bad arguments sent"))?
+                .as_str()
+                .ok_or(anyhow::anyhow!("bad arguments sent"))?;
+            let bytes = get_catpic(our, file_name)?;
+            Ok((bytes, mime_from_extension(file_name).to_string()))
+        }
+        _ => Err(anyhow::anyhow!("bad arguments sent")),
+    }
 }
-fn handle_message(our: &Address, message: &Message, state: &mut State) -> anyhow::Result<()> {
+fn handle_message(
+    our: &Address,
+    message: &Message,
+    state: &mut State,
+    db: &Sqlite,
+) -> anyhow::Result<()> {
     if !message.is_request() {
+        let source = message.source();
+        let is_timer = source.node() == our.node() && source.process == "timer:distro:sys";
+        if is_timer && message.context() == Some(publish::TIMER_CONTEXT) {
+            return publish::advance(state);
+        }
         return Err(anyhow::anyhow!("unexpected Response: {:?}", message));
     }
 
@@ -204,23 +414,61 @@ fn handle_message(our: &Address, message: &Message, state: &mut State) -> anyhow
     let source_node = source.node();
     let pkg = source.package_id().to_string();
     if source_node == our.node() && pkg.as_str() == "terminal:sys" {
-        return handle_terminal(our, body, state);
+        return handle_terminal(our, body, state, db);
+    }
+    if source_node == our.node() && source.process == "http-server:distro:sys" {
+        return http_handlers::handle_frontend(our, body, state, db);
     }
     // let jsonstring = String::from_utf8(body.to_vec())?;
     let req = serde_json::from_slice::<ProviderRequest>(body)?;
     match req {
-        ProviderRequest::API(r) => handle_api_request(r, state),
-        ProviderRequest::MCP(mcp) => match handle_mcp_request(our, mcp, state) {
+        ProviderRequest::API(r) => {
+            // only we manage our keys
+            if source_node != our.node() {
+                return Err(anyhow::anyhow!("rejecting key handling from {}", source));
+            }
+            handle_api_request(r, state)
+        }
+        ProviderRequest::MCP(mcp) => match handle_mcp_request(our, source, mcp, state, db) {
             Ok(_) => Ok(()),
-            Err(e) => {
-                let error_string = e.to_string();
-                let res_body = serde_json::to_vec(&json!({"error": error_string}))?;
-                Response::new().body(res_body).send()?;
-                return Err(e);
+            Err(e) => send_error(e),
+        },
+        ProviderRequest::Schema(name) => match schema_for(state, &name) {
+            Some(schema) => {
+                Response::new().body(serde_json::to_vec(&schema)?).send()?;
+                Ok(())
+            }
+            None => send_error(
+                ProviderError::new(ProviderErrorCode::NotFound, "no such provider here").into(),
+            ),
+        },
+        ProviderRequest::Ping => {
+            let names = served_names(&state.definitions);
+            Response::new()
+                .body(serde_json::to_vec(&json!({ "providers": names }))?)
+                .send()?;
+            Ok(())
+        }
+        ProviderRequest::Pay(claim) => match payments::redeem(source, claim, state) {
+            Ok(receipt) => {
+                Response::new().body(serde_json::to_vec(&receipt)?).send()?;
+                Ok(())
             }
+            Err(e) => send_error(e.into()),
         },
     }
 }
+/// Tells the caller what went wrong, then hands the error on for logging
+fn send_error(e: anyhow::Error) -> anyhow::Result<()> {
+    let error = ProviderError::from(e);
+    let res_body = serde_json::to_vec(&json!({
+        "error": error.message,
+        "code": error.code,
+        "details": error.details,
+    }))?;
+    Response::new().body(res_body).send()?;
+    Err(error.into())
+}
 
 call_init!(init);
 fn init(our: Address) {
@@ -228,10 +476,19 @@ fn init(our: Address) {
     info!("begin");
 
     let mut state = State::load();
+    // keeps the audit_key made for a state that didn't have one
+    state.save();
+    state.definitions = definitions::load_definitions(&our);
+    load_secrets(&our, &mut state);
+    let db = db::load_db(&our).unwrap();
+    if let Err(e) = usage::migrate(&db, &mut state) {
+        error!("couldn't move usage counters into the db: {:?}", e);
+    }
+    let _http_server = http_handlers::init_http().expect("failed to bind paths");
     loop {
         match await_message() {
             Err(send_error) => error!("got SendError: {send_error}"),
-            Ok(ref message) => match handle_message(&our, message, &mut state) {
+            Ok(ref message) => match handle_message(&our, message, &mut state, &db) {
                 Ok(_) => {}
                 Err(e) => error!("got error while handling message: {e:?}"),
             },
@@ -239,7 +496,38 @@ fn init(our: Address) {
     }
 }
 
-fn handle_terminal(_our: &Address, body: &[u8], state: &mut State) -> anyhow::Result<()> {
+/// Reads the encrypted secrets, moving over any plaintext keys left in old state.
+/// The plaintext keys stay in state until the encrypted file holds them
+fn load_secrets(our: &Address, state: &mut State) {
+    state.secrets = match secrets::Secrets::load(our) {
+        Ok(secrets) => secrets,
+        Err(e) => {
+            error!("couldn't load secrets: {:?}", e);
+            secrets::Secrets::unreadable()
+        }
+    };
+    if state.legacy_out_keys.is_empty() {
+        return;
+    }
+    for (name, key) in &state.legacy_out_keys {
+        state.secrets.insert(name.clone(), key.clone());
+    }
+    match state.secrets.save(our) {
+        Ok(_) => {
+            info!("moved plaintext keys into encrypted storage");
+            state.legacy_out_keys.clear();
+            state.save();
+        }
+        Err(e) => error!("couldn't move plaintext keys, keeping them: {:?}", e),
+    }
+}
+
+fn handle_terminal(
+    our: &Address,
+    body: &[u8],
+    state: &mut State,
+    db: &Sqlite,
+) -> anyhow::Result<()> {
     let bod = String::from_utf8(body.to_vec())?;
     let mut words = bod.split_whitespace();
     let command = words.next().ok_or(anyhow::anyhow!("bad command"))?;
@@ -250,18 +538,250 @@ fn handle_terminal(_our: &Address, body: &[u8], state: &mut State) -> anyhow::Re
         "add-key" => {
             let name = words.next().ok_or(anyhow::anyhow!("bad command"))?;
             let key = words.next().ok_or(anyhow::anyhow!("bad command"))?;
-            state.out_keys.insert(name.to_string(), key.to_string());
+            state.secrets.insert(name.to_string(), key.to_string());
+            state.secrets.save(our)?;
         }
         "del-key" => {
             let name = words.next().ok_or(anyhow::anyhow!("bad command"))?;
-            state.out_keys.remove(name);
+            state.secrets.remove(name);
+            state.secrets.save(our)?;
+        }
+        "list-keys" => {
+            for (name, fingerprint) in state.secrets.fingerprints() {
+                info!("{} {}", name, fingerprint);
+            }
+        }
+        "providers" => {
+            let mut names = state.definitions.keys().collect::<Vec<_>>();
+            names.sort();
+            info!("provider definitions: {:?}", names);
+        }
+        // prints the compact JSON to publish as the provider's ~schema note
+        "schema" => {
+            let name = words.collect::<Vec<_>>().join(" ");
+            let schema = schema_for(state, &name).ok_or(anyhow::anyhow!("no such provider"))?;
+            info!("{}", serde_json::to_string(&schema)?);
+        }
+        // publish [--dry-run] <category> <provider name>
+        "publish" => {
+            let mut category = words.next().ok_or(anyhow::anyhow!("bad command"))?;
+            let dry_run = category == "--dry-run";
+            if dry_run {
+                category = words.next().ok_or(anyhow::anyhow!("bad command"))?;
+            }
+            let name = words.collect::<Vec<_>>().join(" ");
+            let def = state
+                .definitions
+                .get(&name)
+                .ok_or(anyhow::anyhow!("no such provider"))?;
+            if let Some(job) = &state.publishing {
+                return Err(anyhow::anyhow!("still publishing {}", job.provider_name()));
+            }
+            state.publishing = publish::publish(our, state, category, def, dry_run)?;
+            publish::advance(state)?;
+        }
+        "set-implementation" => {
+            let address = words.next().ok_or(anyhow::anyhow!("bad command"))?;
+            alloy_primitives::Address::from_str(address)?;
+            state.tba_implementation = Some(address.to_string());
+            state.save();
+        }
+        // flush-cache [provider name]
+        "flush-cache" => {
+            let name = words.collect::<Vec<_>>().join(" ");
+            let provider = Some(name.as_str()).filter(|n| !n.is_empty());
+            db::flush_cache(db, provider)?;
+            info!("flushed cache of {}", provider.unwrap_or("all providers"));
+        }
+        // audit [caller=] [provider_name=] [status=ok|cached|<code>] [since=] [until=] [limit=]
+        "audit" => {
+            let filter = audit::AuditFilter::from_params(&audit_common::params_from_words(words))?;
+            let rows = db::get_audit(db, &filter)?;
+            info!("audit\n{}", serde_json::to_string_pretty(&rows)?);
+        }
+        "breakers" => {
+            info!("circuit breakers\n{:#?}", state.breakers);
+        }
+        "cache-stats" => {
+            info!("cache hits and misses\n{:#?}", state.cache_stats);
+        }
+        // set-rate-limit <calls per minute> <burst> <provider name|*>
+        // set-rate-limit none <provider name|*>
+        "set-rate-limit" => {
+            let limit = match words.next() {
+                Some("none") => None,
+                Some(n) => Some(ratelimit::RateLimit {
+                    per_minute: n.parse()?,
+                    burst: words
+                        .next()
+                        .ok_or(anyhow::anyhow!("bad command"))?
+                        .parse()?,
+                }),
+                None => return Err(anyhow::anyhow!("bad command")),
+            };
+            let name = words.collect::<Vec<_>>().join(" ");
+            if name.is_empty() {
+                return Err(anyhow::anyhow!("bad command"));
+            }
+            match limit {
+                Some(limit) => state.rate_limits.insert(name, limit),
+                None => state.rate_limits.remove(&name),
+            };
+            state.save();
+        }
+        "reload-providers" => {
+            state.definitions = definitions::load_definitions(our);
+        }
+        // m our@provider:hpn:sortugdev.os 'add-provider {"name": "...", "url": "..."}'
+        "add-provider" => {
+            let json = bod
+                .trim_start()
+                .strip_prefix(command)
+                .ok_or(anyhow::anyhow!("bad command"))?;
+            let def = definitions::save_definition(our, &state.definitions, json)?;
+            info!("saved provider definition {}", def.name);
+            state.definitions = definitions::load_definitions(our);
+        }
+        "del-provider" => {
+            let name = words.collect::<Vec<_>>().join(" ");
+            definitions::delete_definition(our, &state.definitions, &name)?;
+            state.definitions = definitions::load_definitions(our);
+        }
+        // set-access public|key <provider name>
+        "set-access" => {
+            let access = match words.next() {
+                Some("public") => Access::Public,
+                Some("key") => Access::Key,
+                _ => return Err(anyhow::anyhow!("bad command")),
+            };
+            let name = words.collect::<Vec<_>>().join(" ");
+            info!("{} is now {:?}", name, access);
+            state.access.insert(name, access);
+            state.save();
+        }
+        // usage [caller]
+        "usage" => {
+            let rows = usage::get(db, words.next(), None)?;
+            info!("usage\n{:#?}", usage::nested(rows));
+        }
+        // set-quota <calls per day>|none <provider name>
+        "set-quota" => {
+            let limit = match words.next() {
+                Some("none") => None,
+                Some(n) => Some(n.parse::<u64>()?),
+                None => return Err(anyhow::anyhow!("bad command")),
+            };
+            let name = words.collect::<Vec<_>>().join(" ");
+            if name.is_empty() {
+                return Err(anyhow::anyhow!("set-quota needs a provider name"));
+            }
+            state.quotas.insert(name, limit);
+            state.save();
+        }
+        // set-credits <wallet> <calls>|none
+        "set-credits" => {
+            let wallet = words.next().ok_or(anyhow::anyhow!("bad command"))?;
+            let credits = match words.next() {
+                Some("none") => None,
+                Some(n) => Some(n.parse::<u64>()?),
+                None => return Err(anyhow::anyhow!("bad command")),
+            };
+            let user = state
+                .in_keys
+                .get_mut(wallet)
+                .ok_or(anyhow::anyhow!("no such user"))?;
+            user.credits = credits;
+            state.save();
+        }
+        "set-chain" => {
+            let chain_id = words.next().ok_or(anyhow::anyhow!("bad command"))?;
+            state.chain_id = chain_id.parse()?;
+            state.save();
         }
         "reset" => {
             let nstate = State::new();
             *state = nstate;
             state.save();
+            state.secrets.save(our)?;
+            state.definitions = definitions::load_definitions(our);
         }
         _ => (),
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pings_list_catpics_with_the_definitions() {
+        assert_eq!(served_names(&HashMap::new()), vec!["Catpics"]);
+        let def = serde_json::from_value::<definitions::ProviderDef>(json!({
+            "name": "Weather",
+            "url": "https://api.example.com/weather",
+        }))
+        .unwrap();
+        let definitions = HashMap::from([("Weather".to_string(), def)]);
+        assert_eq!(served_names(&definitions), vec!["Catpics", "Weather"]);
+    }
+
+    fn code(error: TransportError) -> ProviderErrorCode {
+        classify_transport_error(error, 0).code
+    }
+
+    #[test]
+    fn transport_errors_are_told_apart_by_kind() {
+        assert!(matches!(
+            code(TransportError::Timeout),
+            ProviderErrorCode::UpstreamTimeout
+        ));
+        assert!(matches!(
+            code(TransportError::Offline),
+            ProviderErrorCode::Upstream
+        ));
+        let failed = |detail: &str| {
+            TransportError::Client(HttpClientError::ExecuteRequestFailed(detail.to_string()))
+        };
+        assert!(matches!(
+            code(failed("error sending request: connection refused")),
+            ProviderErrorCode::Upstream
+        ));
+        assert!(matches!(
+            code(failed(
+                "error sending request: dns error: failed to lookup address"
+            )),
+            ProviderErrorCode::UpstreamDns
+        ));
+        let bad_url = HttpClientError::BadUrl {
+            url: "https://x.io/?key=secret".to_string(),
+        };
+        let e = classify_transport_error(TransportError::Client(bad_url), 0);
+        assert!(matches!(e.code, ProviderErrorCode::Internal));
+        assert!(!e.message.contains("secret"));
+    }
+
+    #[test]
+    fn catpic_names_cant_leave_the_pics_folder() {
+        for name in ["cat.png", "grumpy cat.jpeg", "cat-2.v1.gif"] {
+            assert!(check_catpic_name(name).is_ok(), "{} was refused", name);
+        }
+        for name in [
+            "",
+            "../manifest.json",
+            "..",
+            ".hidden",
+            "a/../../b",
+            "sub/cat.png",
+            "/etc/passwd",
+            "..\\secrets",
+            "cat.png\0",
+        ] {
+            assert!(
+                check_catpic_name(name).is_err(),
+                "{:?} was let through",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,480 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use alloy_primitives::{Address as EthAddress, PrimitiveSignature, U256};
+use hyperware_process_lib::eth::{EthAction, EthResponse};
+use hyperware_process_lib::logging::info;
+use hyperware_process_lib::{Address, Request};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::publish;
+use crate::structs::*;
+
+/// keccak256("Transfer(address,address,uint256)")
+const TRANSFER_TOPIC: &str = "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+
+/// what a provider charges, mirrors its ~wallet and ~price notes
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PaymentTerms {
+    /// where payments must go
+    pub wallet: String,
+    /// price of one call, in wei or in the token's smallest unit
+    pub price: String,
+    /// ERC20 contract; native ETH when left out
+    pub token: Option<String>,
+}
+
+/// sent by a caller after paying
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PaymentClaim {
+    pub provider_name: String,
+    pub tx_hash: String,
+    /// node to grant access to, on top of the issued key; only the node sending the claim
+    pub node: Option<String>,
+    /// the payer's EIP-191 signature of claim_message, proving the claim comes from them
+    pub signature: String,
+}
+
+/// what a verified payment bought
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PaymentReceipt {
+    pub api_key: String,
+    /// calls left on the provider paid for, None if the key is unlimited
+    pub credits: Option<u64>,
+}
+
+/// What the payer signs: the tx, the provider and the node sending the claim,
+/// so a claim seen on its way can't be replayed from another node
+pub fn claim_message(provider_name: &str, tx_hash: &str, node: &str) -> String {
+    format!(
+        "hpn payment {} for {} from {}",
+        tx_hash.to_lowercase(),
+        provider_name,
+        node
+    )
+}
+
+/// Checks the claimed tx on chain and turns it into a key with call credits
+/// for the provider paid for. Each tx hash is only ever accepted once, and only
+/// from a node the payer signed the claim for.
+pub fn redeem(
+    source: &Address,
+    claim: PaymentClaim,
+    state: &mut State,
+) -> Result<PaymentReceipt, ProviderError> {
+    let bad = |m: String| ProviderError::new(ProviderErrorCode::PaymentInvalid, m);
+    if claim.node.as_deref().is_some_and(|n| n != source.node()) {
+        return Err(ProviderError::new(
+            ProviderErrorCode::Forbidden,
+            "a claim can only grant access to the node sending it",
+        ));
+    }
+    let terms = state
+        .definitions
+        .get(&claim.provider_name)
+        .and_then(|def| def.payment.clone())
+        .ok_or(ProviderError::new(
+            ProviderErrorCode::NotFound,
+            format!("{} takes no payments", claim.provider_name),
+        ))?;
+    let tx_hash = claim.tx_hash.to_lowercase();
+    if state.used_tx_hashes.contains(&tx_hash) {
+        return Err(bad(format!("{} was already redeemed", tx_hash)));
+    }
+    // callers pay what the notes say, so those are the terms that count
+    let entry_name = state
+        .published
+        .get(&claim.provider_name)
+        .ok_or(ProviderError::new(
+            ProviderErrorCode::NotFound,
+            format!(
+                "{} isn't published, there's nothing to pay against",
+                claim.provider_name
+            ),
+        ))?;
+    let wallet_note = publish::read_note(state.chain_id, entry_name, "~wallet")?;
+    let price_note = publish::read_note(state.chain_id, entry_name, "~price")?;
+    check_published(&terms, wallet_note.as_deref(), price_note.as_deref())?;
+
+    let price = U256::from_str(&terms.price).map_err(|_| anyhow::anyhow!("bad price"))?;
+    if price.is_zero() {
+        return Err(anyhow::anyhow!("price of {} is zero", claim.provider_name).into());
+    }
+    let (payer, amount) = verify_transfer(state.chain_id, &tx_hash, &terms)?;
+    let message = claim_message(&claim.provider_name, &tx_hash, source.node());
+    check_signer(&message, &claim.signature, &payer)?;
+    let credits = credits_for(amount, price)?;
+    info!(
+        "{} paid {} for {} calls to {}",
+        payer, amount, credits, claim.provider_name
+    );
+
+    state.used_tx_hashes.insert(tx_hash.clone());
+    let user = state.in_keys.entry(payer.clone()).or_insert_with(|| User {
+        wallet: payer,
+        tx_hash: tx_hash.clone(),
+        api_key: hex::encode(rand::random::<[u8; 32]>()),
+        node: None,
+        providers: vec![claim.provider_name.clone()],
+        credits: Some(0),
+        paid_credits: HashMap::new(),
+    });
+    user.tx_hash = tx_hash;
+    let paid = user
+        .paid_credits
+        .entry(claim.provider_name.clone())
+        .or_default();
+    *paid = paid.saturating_add(credits);
+    let paid = *paid;
+    if !user.providers.is_empty() && !user.can_call(&claim.provider_name) {
+        user.providers.push(claim.provider_name);
+    }
+    if claim.node.is_some() {
+        user.node = claim.node;
+    }
+    let receipt = PaymentReceipt {
+        api_key: user.api_key.clone(),
+        // keys handed out by hand stay unlimited
+        credits: user.credits.map(|_| paid),
+    };
+    state.save();
+    Ok(receipt)
+}
+
+/// The signature has to be the payer's, over this claim's message
+fn check_signer(message: &str, signature: &str, payer: &str) -> Result<(), ProviderError> {
+    let bad = |m: &str| ProviderError::new(ProviderErrorCode::PaymentInvalid, m);
+    let signature =
+        PrimitiveSignature::from_str(signature).map_err(|_| bad("claim signature is malformed"))?;
+    let signer = signature
+        .recover_address_from_msg(message)
+        .map_err(|_| bad("claim signature is malformed"))?;
+    let payer = EthAddress::from_str(payer).map_err(|_| anyhow::anyhow!("bad payer"))?;
+    if signer != payer {
+        return Err(bad("claim isn't signed by the payer"));
+    }
+    Ok(())
+}
+
+/// The definition's terms have to be the ones published in the ~wallet and ~price notes
+fn check_published(
+    terms: &PaymentTerms,
+    wallet_note: Option<&str>,
+    price_note: Option<&str>,
+) -> Result<(), ProviderError> {
+    let stale = |what: &str| {
+        anyhow::anyhow!(
+            "published {} differs from the definition's, publish again",
+            what
+        )
+    };
+    let wallet = EthAddress::from_str(&terms.wallet).map_err(|_| anyhow::anyhow!("bad wallet"))?;
+    let published_wallet = wallet_note.and_then(|w| EthAddress::from_str(w.trim()).ok());
+    if published_wallet != Some(wallet) {
+        return Err(stale("~wallet").into());
+    }
+    let price = U256::from_str(&terms.price).map_err(|_| anyhow::anyhow!("bad price"))?;
+    let published_price = price_note.and_then(|p| U256::from_str(p.trim()).ok());
+    if published_price != Some(price) {
+        return Err(stale("~price").into());
+    }
+    Ok(())
+}
+
+/// Whole calls the amount pays for, at least one
+fn credits_for(amount: U256, price: U256) -> Result<u64, ProviderError> {
+    if amount < price {
+        return Err(ProviderError::new(
+            ProviderErrorCode::PaymentInvalid,
+            format!("paid {}, price is {}", amount, price),
+        ));
+    }
+    Ok(u64::try_from(amount / price).unwrap_or(u64::MAX))
+}
+
+/// Returns the payer and how much of the right asset reached the wallet
+fn verify_transfer(
+    chain_id: u64,
+    tx_hash: &str,
+    terms: &PaymentTerms,
+) -> Result<(String, U256), ProviderError> {
+    let receipt = eth_rpc(chain_id, "eth_getTransactionReceipt", json!([tx_hash]))?;
+    // token payments are read from the receipt's logs alone
+    let tx = match (&terms.token, receipt.is_null()) {
+        (None, false) => eth_rpc(chain_id, "eth_getTransactionByHash", json!([tx_hash]))?,
+        _ => Value::Null,
+    };
+    check_transfer(&receipt, &tx, terms)
+}
+
+/// Checks a tx and its receipt, as eth_getTransactionByHash and
+/// eth_getTransactionReceipt return them, against the terms
+fn check_transfer(
+    receipt: &Value,
+    tx: &Value,
+    terms: &PaymentTerms,
+) -> Result<(String, U256), ProviderError> {
+    let bad = |m: &str| ProviderError::new(ProviderErrorCode::PaymentInvalid, m);
+    let wallet = EthAddress::from_str(&terms.wallet).map_err(|_| anyhow::anyhow!("bad wallet"))?;
+
+    if receipt.is_null() {
+        return Err(bad("transaction not found or not mined yet"));
+    }
+    if receipt["status"].as_str() != Some("0x1") {
+        return Err(bad("transaction failed"));
+    }
+    match &terms.token {
+        None => {
+            let to = parse_address(&tx["to"]).ok_or(bad("transaction has no recipient"))?;
+            if to != wallet {
+                return Err(bad("transaction was not sent to our wallet"));
+            }
+            let from = parse_address(&tx["from"]).ok_or(bad("transaction has no sender"))?;
+            let value = parse_u256(&tx["value"]).ok_or(bad("transaction has no value"))?;
+            Ok((from.to_string(), value))
+        }
+        Some(token) => {
+            let token = EthAddress::from_str(token).map_err(|_| anyhow::anyhow!("bad token"))?;
+            let logs = receipt["logs"].as_array().cloned().unwrap_or_default();
+            let mut payer = None;
+            let mut total = U256::ZERO;
+            for log in logs {
+                let topics = log["topics"].as_array().cloned().unwrap_or_default();
+                let is_transfer = parse_address(&log["address"]) == Some(token)
+                    && topics.len() == 3
+                    && topics[0].as_str().map(|t| t.to_lowercase()).as_deref()
+                        == Some(TRANSFER_TOPIC);
+                if !is_transfer || topic_address(&topics[2]) != Some(wallet) {
+                    continue;
+                }
+                payer = topic_address(&topics[1]);
+                total += parse_u256(&log["data"]).unwrap_or_default();
+            }
+            let payer = payer.ok_or(bad("no token transfer to our wallet in transaction"))?;
+            Ok((payer.to_string(), total))
+        }
+    }
+}
+
+/// Raw JSON-RPC call through eth:distro:sys
+pub fn eth_rpc(chain_id: u64, method: &str, params: Value) -> anyhow::Result<Value> {
+    let action = EthAction::Request {
+        chain_id,
+        method: method.to_string(),
+        params,
+    };
+    let res = Request::to(("our", "eth", "distro", "sys"))
+        .body(serde_json::to_vec(&action)?)
+        .send_and_await_response(30)??;
+    match serde_json::from_slice::<EthResponse>(res.body())? {
+        EthResponse::Response(value) => Ok(value),
+        EthResponse::Err(e) => Err(anyhow::anyhow!("{} failed: {:?}", method, e)),
+        _ => Err(anyhow::anyhow!("unexpected response to {}", method)),
+    }
+}
+
+fn parse_address(v: &Value) -> Option<EthAddress> {
+    EthAddress::from_str(v.as_str()?).ok()
+}
+
+/// addresses in topics are left padded to 32 bytes
+fn topic_address(v: &Value) -> Option<EthAddress> {
+    let topic = v.as_str()?.trim_start_matches("0x");
+    let tail = topic.get(topic.len().checked_sub(40)?..)?;
+    EthAddress::from_str(tail).ok()
+}
+
+pub fn parse_u256(v: &Value) -> Option<U256> {
+    let hex = v.as_str()?.trim_start_matches("0x");
+    if hex.is_empty() {
+        return Some(U256::ZERO);
+    }
+    U256::from_str_radix(hex, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_signer::SignerSync;
+    use alloy_signer_local::PrivateKeySigner;
+
+    // shaped like the fakechain's (anvil, chain 31337) JSON-RPC answers,
+    // paying from its first default account to its second
+    const PAYER: &str = "0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266";
+    const WALLET: &str = "0x70997970C51812dc3A010C7d01b50e0d17dc79C8";
+    const TOKEN: &str = "0x5FbDB2315678afecb367f032d93F642f64180aa3";
+    // the default accounts' keys, PAYER's and WALLET's
+    const PAYER_KEY: &str = "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+    const OTHER_KEY: &str = "0x59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d";
+
+    fn sign(key: &str, message: &str) -> String {
+        let signer = PrivateKeySigner::from_str(key).unwrap();
+        signer
+            .sign_message_sync(message.as_bytes())
+            .unwrap()
+            .to_string()
+    }
+
+    fn terms(token: Option<&str>) -> PaymentTerms {
+        PaymentTerms {
+            wallet: WALLET.to_lowercase(),
+            price: "1000".to_string(),
+            token: token.map(|t| t.to_string()),
+        }
+    }
+
+    fn receipt(status: &str, logs: Value) -> Value {
+        json!({
+            "transactionHash": "0x6e1f",
+            "blockNumber": "0x5",
+            "from": PAYER.to_lowercase(),
+            "to": WALLET.to_lowercase(),
+            "status": status,
+            "logs": logs,
+        })
+    }
+
+    fn native_tx(to: &str, value: &str) -> Value {
+        json!({
+            "hash": "0x6e1f",
+            "from": PAYER.to_lowercase(),
+            "to": to.to_lowercase(),
+            "value": value,
+            "input": "0x",
+        })
+    }
+
+    fn padded(address: &str) -> String {
+        format!("0x{:0>64}", address.trim_start_matches("0x").to_lowercase())
+    }
+
+    #[test]
+    fn native_payment_to_our_wallet() {
+        let (payer, amount) = check_transfer(
+            &receipt("0x1", json!([])),
+            &native_tx(WALLET, "0xbb8"),
+            &terms(None),
+        )
+        .unwrap();
+        assert_eq!(payer, PAYER);
+        assert_eq!(amount, U256::from(3000));
+        assert_eq!(credits_for(amount, U256::from(1000)).unwrap(), 3);
+    }
+
+    #[test]
+    fn native_payment_elsewhere_is_refused() {
+        let res = check_transfer(
+            &receipt("0x1", json!([])),
+            &native_tx(PAYER, "0xbb8"),
+            &terms(None),
+        );
+        assert!(matches!(
+            res,
+            Err(ProviderError {
+                code: ProviderErrorCode::PaymentInvalid,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn failed_and_missing_txs_are_refused() {
+        let tx = native_tx(WALLET, "0xbb8");
+        for receipt in [receipt("0x0", json!([])), Value::Null] {
+            let res = check_transfer(&receipt, &tx, &terms(None));
+            assert!(matches!(
+                res,
+                Err(ProviderError {
+                    code: ProviderErrorCode::PaymentInvalid,
+                    ..
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn token_payment_sums_transfers_to_our_wallet() {
+        let transfer = |to: &str, amount: u64| {
+            json!({
+                "address": TOKEN.to_lowercase(),
+                "topics": [TRANSFER_TOPIC, padded(PAYER), padded(to)],
+                "data": format!("0x{:064x}", amount),
+            })
+        };
+        let logs = json!([
+            transfer(WALLET, 1500),
+            transfer(PAYER, 9000),
+            transfer(WALLET, 500)
+        ]);
+        let (payer, amount) =
+            check_transfer(&receipt("0x1", logs), &Value::Null, &terms(Some(TOKEN))).unwrap();
+        assert_eq!(payer, PAYER);
+        assert_eq!(amount, U256::from(2000));
+    }
+
+    #[test]
+    fn underpaying_buys_nothing() {
+        let res = credits_for(U256::from(999), U256::from(1000));
+        assert!(matches!(
+            res,
+            Err(ProviderError {
+                code: ProviderErrorCode::PaymentInvalid,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn terms_must_match_the_published_notes() {
+        let terms = terms(None);
+        assert!(check_published(&terms, Some(WALLET), Some("1000")).is_ok());
+        assert!(check_published(&terms, Some(PAYER), Some("1000")).is_err());
+        assert!(check_published(&terms, Some(WALLET), Some("1")).is_err());
+        assert!(check_published(&terms, None, Some("1000")).is_err());
+        assert!(check_published(&terms, Some(WALLET), None).is_err());
+    }
+
+    #[test]
+    fn paid_credits_only_buy_their_provider() {
+        let mut user = User {
+            wallet: PAYER.to_string(),
+            tx_hash: String::new(),
+            api_key: String::new(),
+            node: None,
+            providers: vec![],
+            credits: Some(0),
+            paid_credits: HashMap::from([("cheap".to_string(), 1)]),
+        };
+        assert!(user.has_credit("cheap"));
+        assert!(!user.has_credit("dear"));
+        user.spend_credit("cheap");
+        assert!(!user.has_credit("cheap"));
+
+        let unlimited = User {
+            credits: None,
+            ..user
+        };
+        assert!(unlimited.has_credit("dear"));
+    }
+
+    #[test]
+    fn claims_must_be_signed_by_the_payer_for_the_sending_node() {
+        let message = claim_message("cheap", "0x6E1F", "buyer.os");
+        assert_eq!(message, "hpn payment 0x6e1f for cheap from buyer.os");
+        assert!(check_signer(&message, &sign(PAYER_KEY, &message), PAYER).is_ok());
+
+        // someone else's signature, or the payer's for another node, won't do
+        let res = check_signer(&message, &sign(OTHER_KEY, &message), PAYER);
+        assert!(matches!(
+            res,
+            Err(ProviderError {
+                code: ProviderErrorCode::PaymentInvalid,
+                ..
+            })
+        ));
+        let elsewhere = claim_message("cheap", "0x6e1f", "thief.os");
+        assert!(check_signer(&message, &sign(PAYER_KEY, &elsewhere), PAYER).is_err());
+        assert!(check_signer(&message, "0x1234", PAYER).is_err());
+    }
+}
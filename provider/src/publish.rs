@@ -0,0 +1,526 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+use alloy_consensus::{SignableTransaction, TxEip1559, TxEnvelope};
+use alloy_eips::eip2718::Encodable2718;
+use alloy_primitives::{Address as EthAddress, Bytes, TxKind, B256, U256};
+use alloy_signer::SignerSync;
+use alloy_signer_local::PrivateKeySigner;
+use alloy_sol_types::{sol, SolCall};
+use hyperware_process_lib::logging::info;
+use hyperware_process_lib::{hypermap, timer, Address};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::definitions::ProviderDef;
+use crate::payments::{eth_rpc, parse_u256};
+use crate::structs::*;
+
+// registers a provider on hypermap the way the indexer reads it:
+// <label>.<category>.hpn-testing-beta.os minted by the category's TBA,
+// then one note per fact written by the new entry's TBA.
+// txs are signed with the private key stored as the HPN_PUBLISH_KEY secret,
+// whose wallet must own the category entry.
+const HPN_ROOT_NAME: &str = "hpn-testing-beta.os";
+/// secret holding the hex private key that signs registration txs
+pub const PUBLISH_KEY_SECRET: &str = "HPN_PUBLISH_KEY";
+/// context of the receipt poll timer
+pub const TIMER_CONTEXT: &[u8] = b"publish";
+const RECEIPT_POLLS: u32 = 30;
+const RECEIPT_POLL_MS: u64 = 2_000;
+
+sol! {
+    function mint(address who, bytes label, bytes initialization, bytes erc721Data, address implementation) external returns (address tba);
+    function note(bytes note, bytes data) external returns (bytes32 labelhash);
+    function execute(address to, uint256 value, bytes data, uint8 operation) external payable returns (bytes returnData);
+    function get(bytes32 namehash) external view returns (address tba, address owner, bytes data);
+}
+
+/// what goes into a provider's hypermap entry besides its name, id and payment terms
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Listing {
+    /// hypermap label, the provider name lowercased and hyphenated when left out
+    pub label: Option<String>,
+    pub description: Option<String>,
+    pub site: Option<String>,
+    pub instructions: Option<String>,
+}
+
+/// one transaction to send, or to print on a dry run
+#[derive(Clone, Debug)]
+struct Step {
+    what: String,
+    /// None while the entry it writes to hasn't been minted yet
+    to: Option<EthAddress>,
+    data: Bytes,
+}
+
+/// a publish under way, moved along one receipt poll per timer message
+/// so the process keeps serving calls while txs are mined
+#[derive(Clone, Debug)]
+pub struct Job {
+    provider_name: String,
+    entry_name: String,
+    /// the entry's TBA, once known
+    entry: Option<EthAddress>,
+    /// what's left to send, the front one first
+    steps: VecDeque<Step>,
+    /// the front step's tx, sent and not mined yet
+    sent: Option<Sent>,
+}
+
+#[derive(Clone, Debug)]
+struct Sent {
+    tx_hash: String,
+    polls: u32,
+}
+
+/// what a receipt poll found
+#[derive(Debug, PartialEq)]
+enum Progress {
+    /// the tx isn't mined yet, poll again
+    Waiting,
+    /// the front step landed
+    Landed(String),
+}
+
+/// Mints the provider's entry under `category` (unless it exists) and writes its notes.
+/// Returns the job that sends the txs, see `advance`.
+/// A dry run sends nothing and prints each tx's target and calldata instead.
+pub fn publish(
+    our: &Address,
+    state: &State,
+    category: &str,
+    def: &ProviderDef,
+    dry_run: bool,
+) -> anyhow::Result<Option<Job>> {
+    let signer = match state.secrets.get(PUBLISH_KEY_SECRET) {
+        Some(key) => Some(PrivateKeySigner::from_str(key)?),
+        None if dry_run => None,
+        None => return Err(anyhow::anyhow!("no {} secret set", PUBLISH_KEY_SECRET)),
+    };
+    let implementation = match &state.tba_implementation {
+        Some(address) => EthAddress::from_str(address)?,
+        None if dry_run => EthAddress::ZERO,
+        None => return Err(anyhow::anyhow!("no TBA implementation set")),
+    };
+    let listing = def.listing.clone().unwrap_or_default();
+    let label = listing.label.clone().unwrap_or(default_label(&def.name));
+    let category_name = format!("{}.{}", category, HPN_ROOT_NAME);
+    let entry_name = format!("{}.{}", label, category_name);
+
+    let category_tba = entry_tba(state.chain_id, &category_name)?
+        .ok_or(anyhow::anyhow!("{} isn't minted", category_name))?;
+    let entry = entry_tba(state.chain_id, &entry_name)?;
+    let who = signer.as_ref().map(|s| s.address()).unwrap_or_default();
+    let steps = steps(
+        category_tba,
+        entry,
+        who,
+        implementation,
+        &label,
+        &entry_name,
+        notes(our, def, &listing)?,
+    )?;
+
+    if dry_run {
+        for step in steps {
+            let to = step.to.map(|t| t.to_string());
+            info!(
+                "{}\nto: {}\ndata: 0x{}",
+                step.what,
+                to.as_deref().unwrap_or("<entry TBA, once minted>"),
+                hex::encode(&step.data)
+            );
+        }
+        return Ok(None);
+    }
+    Ok(Some(Job {
+        provider_name: def.name.clone(),
+        entry_name,
+        entry,
+        steps: steps.into(),
+        sent: None,
+    }))
+}
+
+/// The txs that mint the entry, unless it exists, and write its notes
+fn steps(
+    category_tba: EthAddress,
+    entry: Option<EthAddress>,
+    who: EthAddress,
+    implementation: EthAddress,
+    label: &str,
+    entry_name: &str,
+    notes: Vec<(String, String)>,
+) -> anyhow::Result<Vec<Step>> {
+    let hypermap_address = EthAddress::from_str(hypermap::HYPERMAP_ADDRESS)?;
+    let mut steps = vec![];
+    if entry.is_none() {
+        let mint = mintCall {
+            who,
+            label: label.to_string().into_bytes().into(),
+            initialization: Bytes::new(),
+            erc721Data: Bytes::new(),
+            implementation,
+        };
+        steps.push(Step {
+            what: format!("mint {}", entry_name),
+            to: Some(category_tba),
+            data: execute(hypermap_address, mint.abi_encode()),
+        });
+    }
+    for (key, value) in notes {
+        let note = noteCall {
+            note: key.clone().into_bytes().into(),
+            data: value.into_bytes().into(),
+        };
+        steps.push(Step {
+            what: format!("note {} on {}", key, entry_name),
+            to: entry,
+            data: execute(hypermap_address, note.abi_encode()),
+        });
+    }
+    Ok(steps)
+}
+
+/// Moves the job in state along: polls the sent tx's receipt, sends the next
+/// tx once it landed and sets a timer to come back. Drops the job when it's
+/// done or fails.
+pub fn advance(state: &mut State) -> anyhow::Result<()> {
+    let res = step(state);
+    if res.is_err() {
+        state.publishing = None;
+    }
+    res
+}
+
+fn step(state: &mut State) -> anyhow::Result<()> {
+    let chain_id = state.chain_id;
+    let signer = state.secrets.get(PUBLISH_KEY_SECRET).cloned();
+    let Some(job) = state.publishing.as_mut() else {
+        return Ok(());
+    };
+    if let Some(sent) = &job.sent {
+        let receipt = eth_rpc(chain_id, "eth_getTransactionReceipt", json!([sent.tx_hash]))?;
+        match job.on_receipt(&receipt)? {
+            Progress::Waiting => {
+                timer::set_timer(RECEIPT_POLL_MS, Some(TIMER_CONTEXT.to_vec()));
+                return Ok(());
+            }
+            Progress::Landed(what) => info!("{}: done", what),
+        }
+    }
+    let Some(next) = job.steps.front().cloned() else {
+        info!("published {} as {}", job.provider_name, job.entry_name);
+        let (name, entry_name) = (job.provider_name.clone(), job.entry_name.clone());
+        state.publishing = None;
+        state.published.insert(name, entry_name);
+        state.save();
+        return Ok(());
+    };
+    // notes are written by the entry itself, which only exists after the mint lands
+    if next.to.is_none() && job.entry.is_none() {
+        job.entry = entry_tba(chain_id, &job.entry_name)?;
+    }
+    let to = next
+        .to
+        .or(job.entry)
+        .ok_or(anyhow::anyhow!("{} wasn't minted", job.entry_name))?;
+    let signer = PrivateKeySigner::from_str(
+        &signer.ok_or(anyhow::anyhow!("no {} secret set", PUBLISH_KEY_SECRET))?,
+    )?;
+    let tx_hash = send_tx(chain_id, &signer, to, next.data)?;
+    info!("{}: sent {}", next.what, tx_hash);
+    job.sent = Some(Sent { tx_hash, polls: 0 });
+    timer::set_timer(RECEIPT_POLL_MS, Some(TIMER_CONTEXT.to_vec()));
+    Ok(())
+}
+
+impl Job {
+    pub fn provider_name(&self) -> &str {
+        &self.provider_name
+    }
+
+    /// Takes the sent tx's receipt, as eth_getTransactionReceipt returns it
+    fn on_receipt(&mut self, receipt: &Value) -> anyhow::Result<Progress> {
+        let sent = self.sent.as_mut().ok_or(anyhow::anyhow!("no tx sent"))?;
+        if receipt.is_null() {
+            sent.polls += 1;
+            if sent.polls >= RECEIPT_POLLS {
+                return Err(anyhow::anyhow!("{} not mined in time", sent.tx_hash));
+            }
+            return Ok(Progress::Waiting);
+        }
+        if receipt["status"].as_str() != Some("0x1") {
+            return Err(anyhow::anyhow!("{} reverted", sent.tx_hash));
+        }
+        self.sent = None;
+        let step = self
+            .steps
+            .pop_front()
+            .ok_or(anyhow::anyhow!("no step sent"))?;
+        Ok(Progress::Landed(step.what))
+    }
+}
+
+/// The notes the indexer turns into provider columns, skipping ones with nothing to say
+fn notes(
+    our: &Address,
+    def: &ProviderDef,
+    listing: &Listing,
+) -> anyhow::Result<Vec<(String, String)>> {
+    let notes = vec![
+        ("~provider-name", Some(def.name.clone())),
+        ("~provider-id", Some(our.node().to_string())),
+        ("~description", listing.description.clone()),
+        ("~site", listing.site.clone()),
+        ("~instructions", listing.instructions.clone()),
+        ("~wallet", def.payment.as_ref().map(|p| p.wallet.clone())),
+        ("~price", def.payment.as_ref().map(|p| p.price.clone())),
+        ("~schema", Some(serde_json::to_string(&def.input_schema())?)),
+    ];
+    let notes = notes
+        .into_iter()
+        .filter_map(|(key, value)| value.map(|v| (key.to_string(), v)))
+        .collect::<Vec<_>>();
+    for (key, value) in &notes {
+        check_note(key, value)?;
+    }
+    Ok(notes)
+}
+
+/// The indexer only reads notes of printable ASCII (0x20 to 0x7E), anything
+/// else would be published and then dropped
+fn check_note(key: &str, value: &str) -> anyhow::Result<()> {
+    match value.chars().find(|c| !(' '..='~').contains(c)) {
+        None => Ok(()),
+        Some(c) => Err(anyhow::anyhow!(
+            "{} holds {:?}, notes can only hold printable ASCII",
+            key,
+            c
+        )),
+    }
+}
+
+/// hypermap labels are lowercase letters, digits and hyphens
+fn default_label(name: &str) -> String {
+    name.to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
+
+/// calldata for a TBA to call hypermap
+fn execute(hypermap_address: EthAddress, data: Vec<u8>) -> Bytes {
+    executeCall {
+        to: hypermap_address,
+        value: U256::ZERO,
+        data: data.into(),
+        operation: 0,
+    }
+    .abi_encode()
+    .into()
+}
+
+/// The entry's TBA, None if the name isn't minted
+fn entry_tba(chain_id: u64, name: &str) -> anyhow::Result<Option<EthAddress>> {
+    let call = getCall {
+        namehash: B256::from_str(&hypermap::namehash(name))?,
+    };
+    let res = eth_rpc(
+        chain_id,
+        "eth_call",
+        json!([{
+            "to": hypermap::HYPERMAP_ADDRESS,
+            "data": format!("0x{}", hex::encode(call.abi_encode())),
+        }, "latest"]),
+    )?;
+    let bytes = hex::decode(res.as_str().unwrap_or_default().trim_start_matches("0x"))?;
+    let entry = getCall::abi_decode_returns(&bytes, true)?;
+    Ok(Some(entry.tba).filter(|tba| !tba.is_zero()))
+}
+
+/// The note's value on the entry, None if it was never written
+pub fn read_note(chain_id: u64, entry_name: &str, key: &str) -> anyhow::Result<Option<String>> {
+    let call = getCall {
+        namehash: B256::from_str(&hypermap::namehash(&format!("{}.{}", key, entry_name)))?,
+    };
+    let res = eth_rpc(
+        chain_id,
+        "eth_call",
+        json!([{
+            "to": hypermap::HYPERMAP_ADDRESS,
+            "data": format!("0x{}", hex::encode(call.abi_encode())),
+        }, "latest"]),
+    )?;
+    let bytes = hex::decode(res.as_str().unwrap_or_default().trim_start_matches("0x"))?;
+    let note = getCall::abi_decode_returns(&bytes, true)?;
+    Ok(Some(String::from_utf8(note.data.to_vec())?).filter(|n| !n.is_empty()))
+}
+
+/// Signs an EIP-1559 tx and hands it to the chain, returning its hash
+fn send_tx(
+    chain_id: u64,
+    signer: &PrivateKeySigner,
+    to: EthAddress,
+    data: Bytes,
+) -> anyhow::Result<String> {
+    let from = signer.address();
+    let call = json!({
+        "from": from.to_string(),
+        "to": to.to_string(),
+        "data": format!("0x{}", hex::encode(&data)),
+    });
+    let nonce = rpc_u128(
+        chain_id,
+        "eth_getTransactionCount",
+        json!([from.to_string(), "pending"]),
+    )?;
+    let gas = rpc_u128(chain_id, "eth_estimateGas", json!([call]))?;
+    let gas_price = rpc_u128(chain_id, "eth_gasPrice", json!([]))?;
+    let tip = rpc_u128(chain_id, "eth_maxPriorityFeePerGas", json!([])).unwrap_or(0);
+    let tx = TxEip1559 {
+        chain_id,
+        nonce: nonce as u64,
+        // estimates are tight, leave some headroom
+        gas_limit: (gas as u64).saturating_mul(12) / 10,
+        max_fee_per_gas: gas_price.saturating_mul(2).saturating_add(tip),
+        max_priority_fee_per_gas: tip,
+        to: TxKind::Call(to),
+        value: U256::ZERO,
+        access_list: Default::default(),
+        input: data,
+    };
+    let signature = signer.sign_hash_sync(&tx.signature_hash())?;
+    let raw = TxEnvelope::from(tx.into_signed(signature)).encoded_2718();
+    let res = eth_rpc(
+        chain_id,
+        "eth_sendRawTransaction",
+        json!([format!("0x{}", hex::encode(raw))]),
+    )?;
+    res.as_str()
+        .map(|h| h.to_string())
+        .ok_or(anyhow::anyhow!("no tx hash returned"))
+}
+
+fn rpc_u128(chain_id: u64, method: &str, params: Value) -> anyhow::Result<u128> {
+    let res = eth_rpc(chain_id, method, params)?;
+    let n = parse_u256(&res).ok_or(anyhow::anyhow!("bad {} result", method))?;
+    Ok(u128::try_from(n)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CATEGORY_TBA: &str = "0x00000000000000000000000000000000000000c4";
+    const ENTRY_TBA: &str = "0x00000000000000000000000000000000000000e1";
+
+    fn job(steps: Vec<Step>) -> Job {
+        Job {
+            provider_name: "weatherapi".to_string(),
+            entry_name: "weatherapi.weather.hpn-testing-beta.os".to_string(),
+            entry: None,
+            steps: steps.into(),
+            sent: Some(Sent {
+                tx_hash: "0x6e1f".to_string(),
+                polls: 0,
+            }),
+        }
+    }
+
+    fn notes() -> Vec<(String, String)> {
+        vec![
+            ("~provider-name".to_string(), "weatherapi".to_string()),
+            ("~price".to_string(), "1000".to_string()),
+        ]
+    }
+
+    /// what a TBA is told to do with hypermap
+    fn inner(step: &Step) -> Vec<u8> {
+        let call = executeCall::abi_decode(&step.data, true).unwrap();
+        assert_eq!(
+            call.to,
+            EthAddress::from_str(hypermap::HYPERMAP_ADDRESS).unwrap()
+        );
+        call.data.to_vec()
+    }
+
+    #[test]
+    fn unminted_entries_are_minted_by_the_category_first() {
+        let category = EthAddress::from_str(CATEGORY_TBA).unwrap();
+        let steps = steps(
+            category,
+            None,
+            EthAddress::ZERO,
+            EthAddress::ZERO,
+            "weatherapi",
+            "weatherapi.weather.hpn-testing-beta.os",
+            notes(),
+        )
+        .unwrap();
+        assert_eq!(steps.len(), 3);
+        assert_eq!(steps[0].to, Some(category));
+        let mint = mintCall::abi_decode(&inner(&steps[0]), true).unwrap();
+        assert_eq!(mint.label.to_vec(), b"weatherapi");
+        // the entry's TBA is only known once the mint lands
+        assert_eq!(steps[1].to, None);
+        let note = noteCall::abi_decode(&inner(&steps[2]), true).unwrap();
+        assert_eq!(note.note.to_vec(), b"~price");
+        assert_eq!(note.data.to_vec(), b"1000");
+    }
+
+    #[test]
+    fn minted_entries_only_get_notes() {
+        let entry = EthAddress::from_str(ENTRY_TBA).unwrap();
+        let steps = steps(
+            EthAddress::from_str(CATEGORY_TBA).unwrap(),
+            Some(entry),
+            EthAddress::ZERO,
+            EthAddress::ZERO,
+            "weatherapi",
+            "weatherapi.weather.hpn-testing-beta.os",
+            notes(),
+        )
+        .unwrap();
+        assert_eq!(steps.len(), 2);
+        assert!(steps.iter().all(|s| s.to == Some(entry)));
+    }
+
+    #[test]
+    fn a_landed_tx_moves_to_the_next_step() {
+        let step = |what: &str| Step {
+            what: what.to_string(),
+            to: None,
+            data: Bytes::new(),
+        };
+        let mut job = job(vec![step("mint"), step("note")]);
+        assert_eq!(job.on_receipt(&Value::Null).unwrap(), Progress::Waiting);
+        let landed = job.on_receipt(&json!({"status": "0x1"})).unwrap();
+        assert_eq!(landed, Progress::Landed("mint".to_string()));
+        assert!(job.sent.is_none());
+        assert_eq!(job.steps.len(), 1);
+    }
+
+    #[test]
+    fn reverted_and_unmined_txs_fail_the_job() {
+        let mut job = job(vec![]);
+        assert!(job.on_receipt(&json!({"status": "0x0"})).is_err());
+
+        let mut job = self::job(vec![]);
+        for _ in 1..RECEIPT_POLLS {
+            assert_eq!(job.on_receipt(&Value::Null).unwrap(), Progress::Waiting);
+        }
+        assert!(job.on_receipt(&Value::Null).is_err());
+    }
+
+    #[test]
+    fn notes_the_indexer_cant_read_are_refused() {
+        assert!(check_note("~description", "Weather for any city, 3 days out").is_ok());
+        assert!(check_note("~schema", r#"{"type":"object"}"#).is_ok());
+        assert!(check_note("~description", "Météo").is_err());
+        assert!(check_note("~instructions", "line one\nline two").is_err());
+        assert!(check_note("~site", "https://example.com/\t").is_err());
+    }
+}
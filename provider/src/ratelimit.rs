@@ -0,0 +1,152 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::structs::*;
+
+// a token bucket per caller node and provider: it holds up to `burst` calls
+// and refills at `per_minute`. limits are set per provider, "*" covers the
+// providers without one. buckets live in memory only, a restart refills them.
+
+/// key of the limit applying to providers without their own
+pub const DEFAULT_LIMIT: &str = "*";
+/// idle buckets are dropped past this many
+const MAX_BUCKETS: usize = 1024;
+const IDLE_MS: u64 = 600_000;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RateLimit {
+    pub per_minute: u64,
+    pub burst: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Bucket {
+    tokens: f64,
+    updated_ms: u64,
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// Takes a token for the call at `now` (unix ms), or says how long until one is free
+pub fn take(
+    state: &mut State,
+    caller_node: &str,
+    provider_name: &str,
+    now: u64,
+) -> Result<(), ProviderError> {
+    let limit = match state
+        .rate_limits
+        .get(provider_name)
+        .or(state.rate_limits.get(DEFAULT_LIMIT))
+    {
+        Some(limit) => limit.clone(),
+        None => return Ok(()),
+    };
+    if state.buckets.len() > MAX_BUCKETS {
+        state
+            .buckets
+            .retain(|_, b| now.saturating_sub(b.updated_ms) < IDLE_MS);
+    }
+    // tokens per ms
+    let rate = limit.per_minute.max(1) as f64 / 60_000.0;
+    let capacity = limit.burst.max(1) as f64;
+    let bucket = state
+        .buckets
+        .entry((caller_node.to_string(), provider_name.to_string()))
+        .or_insert(Bucket {
+            tokens: capacity,
+            updated_ms: now,
+        });
+    let elapsed = now.saturating_sub(bucket.updated_ms) as f64;
+    bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+    bucket.updated_ms = now;
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        return Ok(());
+    }
+    let retry_after_ms = ((1.0 - bucket.tokens) / rate).ceil() as u64;
+    Err(ProviderError::new(
+        ProviderErrorCode::RateLimited,
+        format!("rate limited, retry after {} ms", retry_after_ms),
+    )
+    .with_details(json!({ "retry_after_ms": retry_after_ms })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limited(per_minute: u64, burst: u64) -> State {
+        let mut state = State::new();
+        state
+            .rate_limits
+            .insert("weather".to_string(), RateLimit { per_minute, burst });
+        state
+    }
+
+    fn retry_after_ms(res: Result<(), ProviderError>) -> u64 {
+        match res {
+            Err(ProviderError {
+                code: ProviderErrorCode::RateLimited,
+                details: Some(details),
+                ..
+            }) => details["retry_after_ms"].as_u64().unwrap(),
+            other => panic!("expected a rate limit, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bursts_are_capped() {
+        let mut state = limited(60, 3);
+        for _ in 0..3 {
+            assert!(take(&mut state, "a.os", "weather", 1_000).is_ok());
+        }
+        assert_eq!(
+            retry_after_ms(take(&mut state, "a.os", "weather", 1_000)),
+            1_000
+        );
+        // each caller and provider has a bucket of its own
+        assert!(take(&mut state, "b.os", "weather", 1_000).is_ok());
+        assert!(take(&mut state, "a.os", "finnhub", 1_000).is_ok());
+    }
+
+    #[test]
+    fn buckets_refill_at_the_rate_up_to_the_burst() {
+        let mut state = limited(60, 2);
+        assert!(take(&mut state, "a.os", "weather", 0).is_ok());
+        assert!(take(&mut state, "a.os", "weather", 0).is_ok());
+        assert_eq!(
+            retry_after_ms(take(&mut state, "a.os", "weather", 400)),
+            600
+        );
+        // one call a second
+        assert!(take(&mut state, "a.os", "weather", 1_000).is_ok());
+        assert!(take(&mut state, "a.os", "weather", 1_000).is_err());
+        // a long wait only refills up to the burst
+        assert!(take(&mut state, "a.os", "weather", 60_000).is_ok());
+        assert!(take(&mut state, "a.os", "weather", 60_000).is_ok());
+        assert!(take(&mut state, "a.os", "weather", 60_000).is_err());
+    }
+
+    #[test]
+    fn the_default_limit_covers_providers_without_one() {
+        let mut state = State::new();
+        assert!(take(&mut state, "a.os", "weather", 0).is_ok());
+        state.rate_limits.insert(
+            DEFAULT_LIMIT.to_string(),
+            RateLimit {
+                per_minute: 1,
+                burst: 1,
+            },
+        );
+        assert!(take(&mut state, "a.os", "weather", 0).is_ok());
+        assert!(take(&mut state, "a.os", "weather", 0).is_err());
+    }
+}
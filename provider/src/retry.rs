@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use hyperware_process_lib::logging::{error, info};
+use hyperware_process_lib::timer::set_and_await_timer;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::definitions::{PreparedCall, ProviderDef};
+use crate::structs::*;
+
+// upstream calls are retried per the provider's `retry` policy, with
+// exponential backoff, inside whatever deadline the caller gave us.
+// a circuit breaker per provider stops calling an upstream that keeps failing:
+// after `breaker_failures` failed calls in a row it opens for `breaker_cooldown_ms`,
+// then one call is let through, closing it on success and reopening it on failure.
+
+pub const DEFAULT_TIMEOUT_S: u64 = 60;
+/// time kept back from the deadline for answering the caller
+const DEADLINE_MARGIN_MS: u64 = 1_000;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RetryPolicy {
+    /// tries in total, 1 never retries
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+    /// first delay, doubled each retry
+    #[serde(default = "default_backoff_ms")]
+    pub backoff_ms: u64,
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    /// upstream statuses worth another try; timeouts, failed lookups and connections always are
+    #[serde(default = "default_retry_statuses")]
+    pub retry_statuses: Vec<u16>,
+    /// safe to send more than once; GET and HEAD are when left out
+    pub idempotent: Option<bool>,
+    #[serde(default = "default_breaker_failures")]
+    pub breaker_failures: u32,
+    #[serde(default = "default_breaker_cooldown_ms")]
+    pub breaker_cooldown_ms: u64,
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: default_attempts(),
+            backoff_ms: default_backoff_ms(),
+            max_backoff_ms: default_max_backoff_ms(),
+            retry_statuses: default_retry_statuses(),
+            idempotent: None,
+            breaker_failures: default_breaker_failures(),
+            breaker_cooldown_ms: default_breaker_cooldown_ms(),
+        }
+    }
+}
+fn default_attempts() -> u32 {
+    1
+}
+fn default_backoff_ms() -> u64 {
+    250
+}
+fn default_max_backoff_ms() -> u64 {
+    4_000
+}
+fn default_retry_statuses() -> Vec<u16> {
+    vec![502, 503, 504]
+}
+fn default_breaker_failures() -> u32 {
+    5
+}
+fn default_breaker_cooldown_ms() -> u64 {
+    30_000
+}
+
+/// consecutive failed calls to one provider
+#[derive(Clone, Debug, Default)]
+pub struct Breaker {
+    failures: u32,
+    open_until_ms: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BreakerState {
+    /// calls go through
+    Closed,
+    /// calls are turned away until the cooldown is over
+    Open { retry_after_ms: u64 },
+    /// the cooldown is over, the next call decides
+    HalfOpen,
+}
+
+impl Breaker {
+    pub fn state(&self, now_ms: u64) -> BreakerState {
+        if self.open_until_ms > now_ms {
+            BreakerState::Open {
+                retry_after_ms: self.open_until_ms - now_ms,
+            }
+        } else if self.open_until_ms > 0 {
+            BreakerState::HalfOpen
+        } else {
+            BreakerState::Closed
+        }
+    }
+
+    /// Counts a finished call: a success closes the breaker, enough failures
+    /// in a row (or one while half-open) open it for the cooldown
+    pub fn record(&mut self, failed: bool, policy: &RetryPolicy, now_ms: u64) -> bool {
+        if !failed {
+            self.failures = 0;
+            self.open_until_ms = 0;
+            return false;
+        }
+        self.failures += 1;
+        let half_open = self.state(now_ms) == BreakerState::HalfOpen;
+        if half_open || self.failures >= policy.breaker_failures.max(1) {
+            self.open_until_ms = now_ms + policy.breaker_cooldown_ms;
+            return true;
+        }
+        false
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// Calls the upstream, retrying per the provider's policy for as long as
+/// `deadline_ms` (counted from now) allows
+pub fn call(
+    breakers: &mut HashMap<String, Breaker>,
+    def: &ProviderDef,
+    call: PreparedCall,
+    deadline_ms: Option<u64>,
+) -> anyhow::Result<ProviderResponse> {
+    let policy = def.retry.clone().unwrap_or_default();
+    let breaker = breakers.entry(def.name.clone()).or_default();
+    if let BreakerState::Open { retry_after_ms } = breaker.state(now_ms()) {
+        let e = ProviderError::new(
+            ProviderErrorCode::Unavailable,
+            format!("{} is failing, retry after {} ms", def.name, retry_after_ms),
+        );
+        return Err(e
+            .with_details(json!({ "retry_after_ms": retry_after_ms }))
+            .into());
+    }
+    let idempotent = policy
+        .idempotent
+        .unwrap_or(matches!(call.method.as_str(), "GET" | "HEAD"));
+    let attempts = if idempotent {
+        policy.attempts.max(1)
+    } else {
+        1
+    };
+    let budget_ms = deadline_ms
+        .unwrap_or(u64::MAX)
+        .saturating_sub(DEADLINE_MARGIN_MS);
+    let timeout_s = def.timeout_s.unwrap_or(DEFAULT_TIMEOUT_S);
+    let started = Instant::now();
+    let mut attempt = 1;
+    let res = loop {
+        let left_ms = budget_ms.saturating_sub(started.elapsed().as_millis() as u64);
+        let attempt_timeout_s = timeout_s.min(left_ms / 1_000).max(1);
+        let res = crate::call_json_api(call.clone(), attempt_timeout_s);
+        if attempt >= attempts || !retryable(&res, &policy) {
+            break res;
+        }
+        let delay_ms = policy
+            .backoff_ms
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(policy.max_backoff_ms);
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+        // not worth retrying without a second left for the next try
+        if elapsed_ms + delay_ms + 1_000 > budget_ms {
+            break res;
+        }
+        info!("retrying {} in {} ms", def.name, delay_ms);
+        let _ = set_and_await_timer(delay_ms);
+        attempt += 1;
+    };
+    if breaker.record(upstream_failed(&res), &policy, now_ms()) {
+        error!(
+            "circuit for {} open after {} failures",
+            def.name, breaker.failures
+        );
+    }
+    res
+}
+
+fn error_code(res: &anyhow::Result<ProviderResponse>) -> Option<&ProviderError> {
+    res.as_ref().err()?.downcast_ref::<ProviderError>()
+}
+
+fn retryable(res: &anyhow::Result<ProviderResponse>, policy: &RetryPolicy) -> bool {
+    let Some(e) = error_code(res) else {
+        return false;
+    };
+    match e.code {
+        ProviderErrorCode::Upstream
+        | ProviderErrorCode::UpstreamTimeout
+        | ProviderErrorCode::UpstreamDns => true,
+        ProviderErrorCode::UpstreamClient | ProviderErrorCode::UpstreamServer => {
+            let status = e.details.as_ref().and_then(|d| d["status"].as_u64());
+            status.is_some_and(|s| policy.retry_statuses.contains(&(s as u16)))
+        }
+        _ => false,
+    }
+}
+
+/// what counts against the breaker: the upstream being down, not the caller's mistakes
+fn upstream_failed(res: &anyhow::Result<ProviderResponse>) -> bool {
+    error_code(res).is_some_and(|e| {
+        matches!(
+            e.code,
+            ProviderErrorCode::Upstream
+                | ProviderErrorCode::UpstreamTimeout
+                | ProviderErrorCode::UpstreamDns
+                | ProviderErrorCode::UpstreamServer
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            breaker_failures: 2,
+            breaker_cooldown_ms: 1_000,
+            ..Default::default()
+        }
+    }
+
+    fn failed(code: ProviderErrorCode, status: Option<u16>) -> anyhow::Result<ProviderResponse> {
+        let e = ProviderError::new(code, "failed");
+        Err(match status {
+            Some(s) => e.with_details(json!({ "status": s })),
+            None => e,
+        }
+        .into())
+    }
+
+    #[test]
+    fn breaker_opens_half_opens_and_closes() {
+        let policy = policy();
+        let mut breaker = Breaker::default();
+        assert_eq!(breaker.state(0), BreakerState::Closed);
+        assert!(!breaker.record(true, &policy, 0));
+        assert_eq!(breaker.state(0), BreakerState::Closed);
+        assert!(breaker.record(true, &policy, 10));
+        assert_eq!(
+            breaker.state(10),
+            BreakerState::Open {
+                retry_after_ms: 1_000
+            }
+        );
+        assert_eq!(breaker.state(1_010), BreakerState::HalfOpen);
+        assert!(!breaker.record(false, &policy, 1_010));
+        assert_eq!(breaker.state(1_010), BreakerState::Closed);
+    }
+
+    #[test]
+    fn a_failure_while_half_open_reopens() {
+        let policy = RetryPolicy {
+            breaker_failures: 3,
+            ..policy()
+        };
+        let mut breaker = Breaker::default();
+        for t in 0..3 {
+            breaker.record(true, &policy, t);
+        }
+        assert_eq!(breaker.state(1_002), BreakerState::HalfOpen);
+        assert!(breaker.record(true, &policy, 1_002));
+        assert!(matches!(breaker.state(1_002), BreakerState::Open { .. }));
+    }
+
+    #[test]
+    fn successes_reset_the_count() {
+        let policy = policy();
+        let mut breaker = Breaker::default();
+        breaker.record(true, &policy, 0);
+        breaker.record(false, &policy, 1);
+        assert!(!breaker.record(true, &policy, 2));
+        assert_eq!(breaker.state(2), BreakerState::Closed);
+    }
+
+    #[test]
+    fn only_transient_failures_are_retried() {
+        let policy = policy();
+        assert!(retryable(
+            &failed(ProviderErrorCode::UpstreamTimeout, None),
+            &policy
+        ));
+        assert!(retryable(
+            &failed(ProviderErrorCode::Upstream, None),
+            &policy
+        ));
+        assert!(retryable(
+            &failed(ProviderErrorCode::UpstreamDns, None),
+            &policy
+        ));
+        assert!(retryable(
+            &failed(ProviderErrorCode::UpstreamServer, Some(503)),
+            &policy
+        ));
+        assert!(!retryable(
+            &failed(ProviderErrorCode::UpstreamServer, Some(500)),
+            &policy
+        ));
+        assert!(!retryable(
+            &failed(ProviderErrorCode::UpstreamClient, Some(404)),
+            &policy
+        ));
+        assert!(!retryable(
+            &failed(ProviderErrorCode::Internal, None),
+            &policy
+        ));
+    }
+
+    #[test]
+    fn callers_mistakes_dont_count_against_the_upstream() {
+        assert!(upstream_failed(&failed(
+            ProviderErrorCode::UpstreamServer,
+            Some(500)
+        )));
+        assert!(!upstream_failed(&failed(
+            ProviderErrorCode::UpstreamClient,
+            Some(404)
+        )));
+        assert!(!upstream_failed(&failed(
+            ProviderErrorCode::BadRequest,
+            None
+        )));
+    }
+}
@@ -0,0 +1,367 @@
+use serde_json::{json, Map, Value};
+
+// the subset of JSON Schema provider arguments need:
+// type, properties, required, additionalProperties, items, enum, const,
+// anyOf/oneOf, min/max(Length|Items), minimum/maximum and their exclusive forms.
+// definitions whose schemas use anything else are refused when loaded,
+// rather than have those keywords quietly let every value through.
+
+/// keywords `validate` enforces
+const KEYWORDS: &[&str] = &[
+    "type",
+    "properties",
+    "required",
+    "additionalProperties",
+    "items",
+    "enum",
+    "const",
+    "anyOf",
+    "oneOf",
+    "minLength",
+    "maxLength",
+    "minItems",
+    "maxItems",
+    "minimum",
+    "maximum",
+    "exclusiveMinimum",
+    "exclusiveMaximum",
+];
+/// keywords that only describe, and need no enforcing
+const ANNOTATIONS: &[&str] = &[
+    "$schema",
+    "$comment",
+    "title",
+    "description",
+    "default",
+    "examples",
+];
+
+/// Refuses schemas with keywords `validate` doesn't enforce, naming the first one found
+pub fn check_supported(schema: &Value) -> Result<(), String> {
+    check_keywords(schema, "")
+}
+
+fn check_keywords(schema: &Value, path: &str) -> Result<(), String> {
+    let schema = match schema {
+        Value::Object(s) => s,
+        Value::Bool(_) => return Ok(()),
+        _ => {
+            return Err(format!(
+                "{}: a schema must be an object or a boolean",
+                field(path)
+            ))
+        }
+    };
+    for (key, value) in schema {
+        if !KEYWORDS.contains(&key.as_str()) && !ANNOTATIONS.contains(&key.as_str()) {
+            return Err(format!("{}: unsupported keyword {}", field(path), key));
+        }
+        let sub_path = format!("{}/{}", path, key);
+        match (key.as_str(), value) {
+            ("properties", Value::Object(properties)) => {
+                for (name, sub_schema) in properties {
+                    check_keywords(sub_schema, &format!("{}/{}", sub_path, name))?;
+                }
+            }
+            ("items" | "additionalProperties", sub_schema) => {
+                check_keywords(sub_schema, &sub_path)?;
+            }
+            ("anyOf" | "oneOf", Value::Array(branches)) => {
+                for (i, branch) in branches.iter().enumerate() {
+                    check_keywords(branch, &format!("{}/{}", sub_path, i))?;
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Checks `value` against `schema`, returning one message per failing field.
+/// An empty list means the value is valid.
+pub fn validate(schema: &Value, value: &Value) -> Vec<String> {
+    validate_at(schema, value, "")
+}
+
+fn check(schema: &Value, value: &Value, path: &str, errors: &mut Vec<String>) {
+    let schema = match schema {
+        Value::Object(s) => s,
+        // `true` and `{}` accept anything, `false` nothing
+        Value::Bool(false) => {
+            errors.push(format!("{}: not allowed", field(path)));
+            return;
+        }
+        _ => return,
+    };
+    if let Some(types) = schema.get("type") {
+        let allowed = match types {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(ts) => ts.iter().filter_map(|t| t.as_str()).collect(),
+            _ => vec![],
+        };
+        if !allowed.is_empty() && !allowed.iter().any(|t| has_type(value, t)) {
+            errors.push(format!(
+                "{}: expected {}, got {}",
+                field(path),
+                allowed.join(" or "),
+                type_name(value)
+            ));
+            return;
+        }
+    }
+    if let Some(Value::Array(options)) = schema.get("enum") {
+        if !options.contains(value) {
+            errors.push(format!(
+                "{}: must be one of {}",
+                field(path),
+                json!(options)
+            ));
+        }
+    }
+    if let Some(constant) = schema.get("const") {
+        if constant != value {
+            errors.push(format!("{}: must be {}", field(path), constant));
+        }
+    }
+    for key in ["anyOf", "oneOf"] {
+        if let Some(Value::Array(branches)) = schema.get(key) {
+            let matches = branches
+                .iter()
+                .filter(|b| validate_at(b, value, path).is_empty())
+                .count();
+            let ok = if key == "anyOf" {
+                matches > 0
+            } else {
+                matches == 1
+            };
+            if !ok {
+                errors.push(format!(
+                    "{}: matches none of the allowed shapes",
+                    field(path)
+                ));
+            }
+        }
+    }
+    match value {
+        Value::String(s) => {
+            let len = s.chars().count() as u64;
+            if let Some(min) = uint(schema, "minLength").filter(|m| len < *m) {
+                errors.push(format!("{}: shorter than {}", field(path), min));
+            }
+            if let Some(max) = uint(schema, "maxLength").filter(|m| len > *m) {
+                errors.push(format!("{}: longer than {}", field(path), max));
+            }
+        }
+        Value::Number(n) => {
+            let n = n.as_f64().unwrap_or_default();
+            let number = |key: &str| schema.get(key).and_then(|v| v.as_f64());
+            if number("minimum").is_some_and(|m| n < m)
+                || number("exclusiveMinimum").is_some_and(|m| n <= m)
+            {
+                errors.push(format!("{}: too small", field(path)));
+            }
+            if number("maximum").is_some_and(|m| n > m)
+                || number("exclusiveMaximum").is_some_and(|m| n >= m)
+            {
+                errors.push(format!("{}: too large", field(path)));
+            }
+        }
+        Value::Array(items) => {
+            let len = items.len() as u64;
+            if let Some(min) = uint(schema, "minItems").filter(|m| len < *m) {
+                errors.push(format!("{}: fewer than {} items", field(path), min));
+            }
+            if let Some(max) = uint(schema, "maxItems").filter(|m| len > *m) {
+                errors.push(format!("{}: more than {} items", field(path), max));
+            }
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    check(item_schema, item, &format!("{}/{}", path, i), errors);
+                }
+            }
+        }
+        Value::Object(obj) => check_object(schema, obj, path, errors),
+        _ => (),
+    }
+}
+
+fn check_object(
+    schema: &Map<String, Value>,
+    obj: &Map<String, Value>,
+    path: &str,
+    errors: &mut Vec<String>,
+) {
+    if let Some(Value::Array(required)) = schema.get("required") {
+        for name in required.iter().filter_map(|r| r.as_str()) {
+            if !obj.contains_key(name) {
+                errors.push(format!("{}/{}: required", path, name));
+            }
+        }
+    }
+    let properties = schema.get("properties").and_then(|p| p.as_object());
+    for (name, v) in obj {
+        let sub_path = format!("{}/{}", path, name);
+        match properties.and_then(|p| p.get(name)) {
+            Some(sub_schema) => check(sub_schema, v, &sub_path, errors),
+            None => match schema.get("additionalProperties") {
+                Some(Value::Bool(false)) => errors.push(format!("{}: unknown field", sub_path)),
+                Some(extra) => check(extra, v, &sub_path, errors),
+                None => (),
+            },
+        }
+    }
+}
+
+fn validate_at(schema: &Value, value: &Value, path: &str) -> Vec<String> {
+    let mut errors = vec![];
+    check(schema, value, path, &mut errors);
+    errors
+}
+
+fn field(path: &str) -> &str {
+    if path.is_empty() {
+        "arguments"
+    } else {
+        path
+    }
+}
+
+fn uint(schema: &Map<String, Value>, key: &str) -> Option<u64> {
+    schema.get(key).and_then(|v| v.as_u64())
+}
+
+fn has_type(value: &Value, t: &str) -> bool {
+    match t {
+        "integer" => value.as_i64().is_some() || value.as_u64().is_some(),
+        _ => type_name(value) == t || (t == "number" && value.is_number()),
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "symbol": {"type": "string", "minLength": 1, "maxLength": 5},
+                "days": {"type": "integer", "minimum": 1, "maximum": 14},
+                "units": {"enum": ["metric", "imperial"]},
+                "location": {
+                    "type": "object",
+                    "properties": {
+                        "lat": {"type": "number", "minimum": -90, "maximum": 90},
+                        "lon": {"type": "number"}
+                    },
+                    "required": ["lat", "lon"],
+                    "additionalProperties": false
+                }
+            },
+            "required": ["symbol"]
+        })
+    }
+
+    #[test]
+    fn valid_arguments_pass() {
+        let value = json!({
+            "symbol": "AAPL",
+            "days": 3,
+            "units": "metric",
+            "location": {"lat": 52.5, "lon": 13.4}
+        });
+        assert_eq!(validate(&schema(), &value), Vec::<String>::new());
+    }
+
+    #[test]
+    fn types_are_checked() {
+        let errors = validate(&schema(), &json!({"symbol": 5, "days": 1.5}));
+        assert_eq!(
+            errors,
+            vec![
+                "/days: expected integer, got number",
+                "/symbol: expected string, got number"
+            ]
+        );
+        assert_eq!(
+            validate(&schema(), &json!([])),
+            vec!["arguments: expected object, got array"]
+        );
+    }
+
+    #[test]
+    fn required_fields_are_checked() {
+        assert_eq!(validate(&schema(), &json!({})), vec!["/symbol: required"]);
+    }
+
+    #[test]
+    fn enums_are_checked() {
+        let errors = validate(&schema(), &json!({"symbol": "A", "units": "kelvin"}));
+        assert_eq!(
+            errors,
+            vec![r#"/units: must be one of ["metric","imperial"]"#]
+        );
+    }
+
+    #[test]
+    fn bounds_are_checked() {
+        let errors = validate(&schema(), &json!({"symbol": "", "days": 0}));
+        assert_eq!(errors, vec!["/days: too small", "/symbol: shorter than 1"]);
+        let errors = validate(&schema(), &json!({"symbol": "TOOLONG", "days": 15}));
+        assert_eq!(errors, vec!["/days: too large", "/symbol: longer than 5"]);
+    }
+
+    #[test]
+    fn nested_objects_are_checked() {
+        let value = json!({"symbol": "A", "location": {"lat": 91, "alt": 3}});
+        let errors = validate(&schema(), &value);
+        assert_eq!(
+            errors,
+            vec![
+                "/location/lon: required",
+                "/location/alt: unknown field",
+                "/location/lat: too large"
+            ]
+        );
+    }
+
+    #[test]
+    fn one_of_wants_exactly_one_match() {
+        let schema = json!({"oneOf": [{"type": "integer"}, {"type": "number"}]});
+        assert!(validate(&schema, &json!(1.5)).is_empty());
+        assert!(!validate(&schema, &json!(1)).is_empty());
+    }
+
+    #[test]
+    fn supported_schemas_are_accepted() {
+        assert_eq!(check_supported(&schema()), Ok(()));
+        let described = json!({"type": "string", "description": "a ticker", "default": "AAPL"});
+        assert_eq!(check_supported(&described), Ok(()));
+    }
+
+    #[test]
+    fn unsupported_keywords_are_refused() {
+        let pattern = json!({"properties": {"symbol": {"type": "string", "pattern": "^[A-Z]+$"}}});
+        assert_eq!(
+            check_supported(&pattern),
+            Err("/properties/symbol: unsupported keyword pattern".to_string())
+        );
+        let reference = json!({"items": {"$ref": "#/$defs/x"}});
+        assert!(check_supported(&reference).is_err());
+        let format = json!({"anyOf": [{"type": "string", "format": "date"}]});
+        assert!(check_supported(&format).is_err());
+        let all_of = json!({"allOf": [{"type": "string"}]});
+        assert!(check_supported(&all_of).is_err());
+    }
+}
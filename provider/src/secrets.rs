@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hyperware_process_lib::net::NetAction;
+use hyperware_process_lib::vfs::{create_drive, create_file, open_file};
+use hyperware_process_lib::{Address, Request};
+use sha2::{Digest, Sha256};
+
+// upstream API keys, kept apart from State and encrypted at rest.
+// the encryption key is derived from our node's networking key:
+// net:distro:sys signs a fixed message (ed25519 signatures are deterministic)
+// and the signature is hashed into a ChaCha20-Poly1305 key.
+// a node whose networking key changes can't read its old secrets.
+const DRIVE: &str = "secrets";
+const FILE: &str = "secrets.bin";
+const KEY_CONTEXT: &[u8] = b"hpn provider secrets v1";
+const NONCE_LEN: usize = 12;
+
+#[derive(Clone, Default)]
+pub struct Secrets {
+    keys: HashMap<String, String>,
+    /// the file exists but couldn't be read; saving would overwrite it for good
+    unreadable: bool,
+}
+
+/// names only, values must never reach the logs
+impl std::fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut names = self.keys.keys().collect::<Vec<_>>();
+        names.sort();
+        f.debug_struct("Secrets")
+            .field("names", &names)
+            .field("unreadable", &self.unreadable)
+            .finish()
+    }
+}
+
+impl Secrets {
+    pub fn load(our: &Address) -> anyhow::Result<Self> {
+        let path = secrets_path(our)?;
+        let bytes = match open_file(&path, false, None).and_then(|f| f.read()) {
+            Ok(bytes) if !bytes.is_empty() => bytes,
+            _ => return Ok(Self::default()),
+        };
+        if bytes.len() < NONCE_LEN {
+            return Err(anyhow::anyhow!("secrets file is corrupt"));
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let plaintext = cipher()?
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("couldn't decrypt secrets"))?;
+        Ok(Self {
+            keys: serde_json::from_slice(&plaintext)?,
+            unreadable: false,
+        })
+    }
+
+    /// Stands in for a file that failed to load, refusing to be saved over it
+    pub fn unreadable() -> Self {
+        Self {
+            keys: HashMap::new(),
+            unreadable: true,
+        }
+    }
+
+    /// Encrypts under a fresh nonce and overwrites the file
+    pub fn save(&self, our: &Address) -> anyhow::Result<()> {
+        if self.unreadable {
+            return Err(anyhow::anyhow!(
+                "secrets file couldn't be read, not overwriting it; `reset` starts over"
+            ));
+        }
+        let plaintext = serde_json::to_vec(&self.keys)?;
+        let nonce = rand::random::<[u8; NONCE_LEN]>();
+        let ciphertext = cipher()?
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+            .map_err(|_| anyhow::anyhow!("couldn't encrypt secrets"))?;
+        let file = create_file(&secrets_path(our)?, None)?;
+        file.write(&[nonce.as_slice(), ciphertext.as_slice()].concat())?;
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.keys.get(name)
+    }
+
+    pub fn insert(&mut self, name: String, value: String) {
+        self.keys.insert(name, value);
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        self.keys.remove(name)
+    }
+
+    /// name and the first 8 bytes of the value's sha256, sorted by name
+    pub fn fingerprints(&self) -> Vec<(String, String)> {
+        let mut prints = self
+            .keys
+            .iter()
+            .map(|(name, value)| {
+                let digest = Sha256::digest(value.as_bytes());
+                (name.clone(), hex::encode(&digest[..8]))
+            })
+            .collect::<Vec<_>>();
+        prints.sort();
+        prints
+    }
+}
+
+fn secrets_path(our: &Address) -> anyhow::Result<String> {
+    let drive = create_drive(our.package_id(), DRIVE, None)?;
+    Ok(format!("{}/{}", drive, FILE))
+}
+
+fn cipher() -> anyhow::Result<ChaCha20Poly1305> {
+    let res = Request::to(("our", "net", "distro", "sys"))
+        .body(rmp_serde::to_vec(&NetAction::Sign)?)
+        .blob_bytes(KEY_CONTEXT)
+        .send_and_await_response(5)??;
+    let signature = res
+        .blob()
+        .ok_or(anyhow::anyhow!("net returned no signature"))?;
+    let key = Sha256::digest(signature.bytes());
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
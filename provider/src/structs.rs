@@ -1,24 +1,98 @@
 use core::str;
-use hyperware_process_lib::{get_state, println, set_state};
-use std::collections::HashMap;
+use hyperware_process_lib::{get_state, hypermap, println, set_state};
+use std::collections::{HashMap, HashSet};
 
 use serde::{Deserialize, Serialize};
 
-// add from terminal
-// ex:
-// m our@provider:hpn:sortugdev.os 'add-key WEATHER_API_KEY 1ef55da5e2844b1995b115659251104'
-#[derive(Clone, Debug, Deserialize, Serialize)]
+use crate::audit_common::new_audit_key;
+use crate::cache::CacheStats;
+use crate::definitions::ProviderDef;
+use crate::publish::Job;
+use crate::ratelimit::{Bucket, RateLimit};
+use crate::retry::Breaker;
+use crate::secrets::Secrets;
+use crate::usage::Usage;
+
+#[derive(Clone, Deserialize, Serialize)]
 pub struct State {
-    pub out_keys: HashMap<String, String>,
+    /// plaintext upstream keys from before secrets.rs, kept until they're moved over
+    #[serde(
+        rename = "out_keys",
+        default,
+        skip_serializing_if = "HashMap::is_empty"
+    )]
+    pub legacy_out_keys: HashMap<String, String>,
     pub in_keys: HashMap<String, User>,
+    /// per provider overrides of the access set in its definition
+    #[serde(default)]
+    pub access: HashMap<String, Access>,
+    /// chain payments are checked and registrations published on, set-chain 31337 for the fakechain
+    #[serde(default = "default_chain_id")]
+    pub chain_id: u64,
+    /// payment txs already turned into credits
+    #[serde(default)]
+    pub used_tx_hashes: HashSet<String>,
+    /// provider name -> hypermap entry it was published as, whose notes payments are checked against
+    #[serde(default)]
+    pub published: HashMap<String, String>,
+    /// counters from before they moved to the db, see usage::migrate
+    #[serde(rename = "usage", default, skip_serializing_if = "HashMap::is_empty")]
+    pub legacy_usage: HashMap<String, HashMap<String, Usage>>,
+    /// per provider daily call limits, overriding the definition; None lifts the limit
+    #[serde(default)]
+    pub quotas: HashMap<String, Option<u64>>,
+    /// TBA implementation new hypermap entries are minted with, see publish.rs
+    #[serde(default)]
+    pub tba_implementation: Option<String>,
+    /// provider name, or "*" for the rest -> token bucket settings, see ratelimit.rs
+    #[serde(default)]
+    pub rate_limits: HashMap<String, RateLimit>,
+    /// provider name -> circuit breaker, see retry.rs
+    #[serde(skip)]
+    pub breakers: HashMap<String, Breaker>,
+    /// (caller node, provider name) -> bucket
+    #[serde(skip)]
+    pub buckets: HashMap<(String, String), Bucket>,
+    /// provider name -> cache hits and misses since the process started
+    #[serde(skip)]
+    pub cache_stats: HashMap<String, CacheStats>,
+    /// keys the argument digests in the audit table, see audit.rs
+    #[serde(default = "new_audit_key")]
+    pub audit_key: String,
+    /// the publish under way, see publish.rs
+    #[serde(skip)]
+    pub publishing: Option<Job>,
+    /// loaded from the VFS on start, see definitions.rs
+    #[serde(skip)]
+    pub definitions: HashMap<String, ProviderDef>,
+    /// upstream API keys, encrypted in their own file, see secrets.rs
+    // add from terminal
+    // ex:
+    // m our@provider:hpn:sortugdev.os 'add-key WEATHER_API_KEY 1ef55da5e2844b1995b115659251104'
+    #[serde(skip)]
+    pub secrets: Secrets,
 }
 impl State {
     pub fn new() -> Self {
-        let state = State {
-            out_keys: HashMap::new(),
+        State {
+            legacy_out_keys: HashMap::new(),
             in_keys: HashMap::new(),
-        };
-        state
+            access: HashMap::new(),
+            chain_id: default_chain_id(),
+            used_tx_hashes: HashSet::new(),
+            published: HashMap::new(),
+            legacy_usage: HashMap::new(),
+            quotas: HashMap::new(),
+            tba_implementation: None,
+            rate_limits: HashMap::new(),
+            buckets: HashMap::new(),
+            breakers: HashMap::new(),
+            cache_stats: HashMap::new(),
+            audit_key: new_audit_key(),
+            publishing: None,
+            definitions: HashMap::new(),
+            secrets: Secrets::default(),
+        }
     }
     pub fn load() -> Self {
         match get_state() {
@@ -33,11 +107,6 @@ impl State {
         }
     }
 
-    /// Reset by removing the checkpoint and reloading fresh state
-    // pub fn reset(&self) {
-    //     clear_state();
-    // }
-
     /// Saves a checkpoint, serializes to the current block
     pub fn save(&mut self) {
         match serde_json::to_vec(self) {
@@ -47,9 +116,286 @@ impl State {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+/// what the terminal's `state` prints: keys show by name only, never by value
+impl std::fmt::Debug for State {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut legacy_names = self.legacy_out_keys.keys().collect::<Vec<_>>();
+        legacy_names.sort();
+        f.debug_struct("State")
+            .field("legacy_out_keys", &legacy_names)
+            .field("in_keys", &self.in_keys)
+            .field("access", &self.access)
+            .field("chain_id", &self.chain_id)
+            .field("used_tx_hashes", &self.used_tx_hashes)
+            .field("published", &self.published)
+            .field("legacy_usage", &self.legacy_usage)
+            .field("quotas", &self.quotas)
+            .field("tba_implementation", &self.tba_implementation)
+            .field("rate_limits", &self.rate_limits)
+            .field("breakers", &self.breakers)
+            .field("buckets", &self.buckets)
+            .field("cache_stats", &self.cache_stats)
+            .field("audit_key", &REDACTED)
+            .field("publishing", &self.publishing)
+            .field("definitions", &self.definitions)
+            .field("secrets", &self.secrets)
+            .finish()
+    }
+}
+
+/// stands in for values that must never reach the logs
+const REDACTED: &str = "<redacted>";
+
+fn default_chain_id() -> u64 {
+    hypermap::HYPERMAP_CHAIN_ID
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 pub struct User {
     pub wallet: String,
     pub tx_hash: String,
     pub api_key: String,
+    /// node allowed to call without sending the key
+    #[serde(default)]
+    pub node: Option<String>,
+    /// providers this user may call, empty means all of them
+    #[serde(default)]
+    pub providers: Vec<String>,
+    /// calls left on any provider, None for unlimited keys handed out by hand
+    #[serde(default)]
+    pub credits: Option<u64>,
+    /// provider name -> calls bought on chain, only good for that provider
+    #[serde(default)]
+    pub paid_credits: HashMap<String, u64>,
+}
+impl User {
+    pub fn can_call(&self, provider_name: &str) -> bool {
+        self.providers.is_empty() || self.providers.iter().any(|p| p == provider_name)
+    }
+    /// Whether a call to the provider is paid for: an unlimited key, credits
+    /// bought for that provider, or credits good for any of them
+    pub fn has_credit(&self, provider_name: &str) -> bool {
+        self.credits != Some(0) || self.paid_credits.get(provider_name).is_some_and(|c| *c > 0)
+    }
+    /// Uses up one call, out of the provider's paid credits first
+    pub fn spend_credit(&mut self, provider_name: &str) {
+        match self.paid_credits.get_mut(provider_name) {
+            Some(paid) if *paid > 0 => *paid -= 1,
+            _ => self.credits = self.credits.map(|c| c.saturating_sub(1)),
+        }
+    }
+}
+
+impl std::fmt::Debug for User {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("User")
+            .field("wallet", &self.wallet)
+            .field("tx_hash", &self.tx_hash)
+            .field("api_key", &REDACTED)
+            .field("node", &self.node)
+            .field("providers", &self.providers)
+            .field("credits", &self.credits)
+            .field("paid_credits", &self.paid_credits)
+            .finish()
+    }
+}
+
+/// who may call a provider
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    /// anyone
+    #[default]
+    Public,
+    /// callers with a key in in_keys, or a node granted access
+    Key,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum ProviderErrorCode {
+    BadRequest,
+    Unauthorized,
+    /// the payment tx doesn't check out
+    PaymentInvalid,
+    /// out of credits or over the daily limit
+    QuotaExceeded,
+    /// too many calls too fast, details hold retry_after_ms
+    RateLimited,
+    /// the provider's circuit breaker is open, details hold retry_after_ms
+    Unavailable,
+    /// the call would leave for a host the provider may not reach
+    Forbidden,
+    NotFound,
+    /// the upstream API couldn't be reached
+    Upstream,
+    /// the upstream API didn't answer in time
+    UpstreamTimeout,
+    /// the upstream host didn't resolve
+    UpstreamDns,
+    /// the upstream API answered 4xx, details hold its response
+    UpstreamClient,
+    /// the upstream API answered 5xx, details hold its response
+    UpstreamServer,
+    Internal,
+}
+
+/// what a successful MCP call sends back
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProviderResponse {
+    /// upstream status code
+    pub status: u16,
+    /// the upstream headers in FORWARDED_HEADERS
+    pub headers: HashMap<String, String>,
+    /// MIME type of the body, from the upstream's content-type or sniffed
+    #[serde(default)]
+    pub mime: String,
+    /// parsed JSON, or text; null when the body is binary or over
+    /// INLINE_LIMIT and travels as the blob instead
+    pub body: serde_json::Value,
+    /// time spent waiting on the upstream
+    pub latency_ms: u64,
+    /// answered from the cache, see cache.rs
+    #[serde(default)]
+    pub cached: bool,
+    #[serde(skip)]
+    pub blob: Option<Vec<u8>>,
+}
+/// bodies larger than this go as the blob even when they are text
+pub const INLINE_LIMIT: usize = 64 * 1024;
+/// upstream headers worth passing on, lowercase
+pub const FORWARDED_HEADERS: [&str; 7] = [
+    "content-type",
+    "cache-control",
+    "etag",
+    "retry-after",
+    "x-ratelimit-limit",
+    "x-ratelimit-remaining",
+    "x-ratelimit-reset",
+];
+impl ProviderResponse {
+    pub fn new(
+        status: u16,
+        headers: HashMap<String, String>,
+        bytes: Vec<u8>,
+        latency_ms: u64,
+    ) -> Self {
+        let declared = headers
+            .get("content-type")
+            .and_then(|c| c.split(';').next())
+            .map(|c| c.trim().to_lowercase());
+        let (body, blob, sniffed) = if bytes.len() > INLINE_LIMIT {
+            (
+                serde_json::Value::Null,
+                Some(bytes),
+                "application/octet-stream",
+            )
+        } else {
+            match serde_json::from_slice(&bytes) {
+                Ok(json) => (json, None, "application/json"),
+                Err(_) => match String::from_utf8(bytes) {
+                    Ok(text) => (serde_json::Value::String(text), None, "text/plain"),
+                    Err(e) => (
+                        serde_json::Value::Null,
+                        Some(e.into_bytes()),
+                        "application/octet-stream",
+                    ),
+                },
+            }
+        };
+        Self {
+            status,
+            headers,
+            mime: declared.unwrap_or(sniffed.to_string()),
+            body,
+            latency_ms,
+            cached: false,
+            blob,
+        }
+    }
+    /// size of the body as sent, for usage
+    pub fn body_len(&self) -> usize {
+        match &self.blob {
+            Some(blob) => blob.len(),
+            None => self.body.to_string().len(),
+        }
+    }
+    /// 4xx and 5xx answers become errors carrying the whole response
+    pub fn into_result(self) -> Result<Self, ProviderError> {
+        let code = match self.status {
+            400..=499 => ProviderErrorCode::UpstreamClient,
+            500..=599 => ProviderErrorCode::UpstreamServer,
+            _ => return Ok(self),
+        };
+        let message = format!("upstream answered {}", self.status);
+        let details = serde_json::to_value(&self).unwrap_or_default();
+        Err(ProviderError::new(code, message).with_details(details))
+    }
+}
+
+/// sent back to callers as {"error": message, "code": code, "details": details}
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProviderError {
+    pub code: ProviderErrorCode,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+impl ProviderError {
+    pub fn new(code: ProviderErrorCode, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+            details: None,
+        }
+    }
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+}
+impl std::fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+impl std::error::Error for ProviderError {}
+impl From<anyhow::Error> for ProviderError {
+    /// keeps ProviderErrors raised deeper down, anything else is internal
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<ProviderError>() {
+            Ok(provider_error) => provider_error,
+            Err(e) => Self::new(ProviderErrorCode::Internal, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_dumps_leave_keys_out() {
+        let mut state = State::new();
+        state
+            .legacy_out_keys
+            .insert("WEATHER_API_KEY".to_string(), "upstream-secret".to_string());
+        state.in_keys.insert(
+            "0xabc".to_string(),
+            User {
+                wallet: "0xabc".to_string(),
+                tx_hash: "0x1".to_string(),
+                api_key: "issued-secret".to_string(),
+                node: None,
+                providers: vec![],
+                credits: None,
+                paid_credits: HashMap::new(),
+            },
+        );
+        let dump = format!("{:#?}", state);
+        assert!(dump.contains("WEATHER_API_KEY"));
+        assert!(dump.contains("0xabc"));
+        for secret in ["upstream-secret", "issued-secret", &state.audit_key] {
+            assert!(!dump.contains(secret), "{} leaked", secret);
+        }
+    }
 }
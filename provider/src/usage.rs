@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyperware_process_lib::logging::error;
+use hyperware_process_lib::sqlite::Sqlite;
+use hyperware_process_lib::Address;
+use serde::{Deserialize, Serialize};
+
+use crate::auth;
+use crate::db as dbm;
+use crate::structs::*;
+
+// counters live in the provider db, one row per caller and provider,
+// so counting a call doesn't rewrite the whole process state.
+// cache hits are counted as calls but are free: they neither use up a credit
+// nor count towards the daily limit, since they never reach the upstream API.
+
+/// counters for one caller on one provider
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct Usage {
+    pub calls: u64,
+    /// response bytes sent back
+    pub bytes: u64,
+    pub errors: u64,
+    /// calls answered from the cache, also counted in calls
+    #[serde(default)]
+    pub cache_hits: u64,
+    /// day (days since epoch) day_calls counts for
+    pub day: u64,
+    /// upstream calls made that day
+    pub day_calls: u64,
+}
+
+/// How a call ended
+pub enum Outcome {
+    /// answered by the upstream API, with the response's bytes
+    Served(usize),
+    /// answered from the cache
+    Cached(usize),
+    Failed,
+}
+
+impl Outcome {
+    pub fn of(res: &anyhow::Result<ProviderResponse>) -> Self {
+        match res {
+            Ok(r) if r.cached => Outcome::Cached(r.body_len()),
+            Ok(r) => Outcome::Served(r.body_len()),
+            // callers aren't charged for upstream failures
+            Err(_) => Outcome::Failed,
+        }
+    }
+
+    pub fn uses_credit(&self) -> bool {
+        matches!(self, Outcome::Served(_))
+    }
+}
+
+impl Usage {
+    /// Counts one call made on `day`
+    pub fn add(&mut self, outcome: &Outcome, day: u64) {
+        if self.day != day {
+            self.day = day;
+            self.day_calls = 0;
+        }
+        self.calls += 1;
+        match outcome {
+            Outcome::Served(bytes) => {
+                self.bytes += *bytes as u64;
+                self.day_calls += 1;
+            }
+            Outcome::Cached(bytes) => {
+                self.bytes += *bytes as u64;
+                self.cache_hits += 1;
+            }
+            Outcome::Failed => {
+                self.errors += 1;
+                self.day_calls += 1;
+            }
+        }
+    }
+
+    /// Upstream calls made on `day`
+    pub fn calls_on(&self, day: u64) -> u64 {
+        match self.day == day {
+            true => self.day_calls,
+            false => 0,
+        }
+    }
+}
+
+/// a usage row as stored in the db
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UsageRow {
+    pub caller: String,
+    pub provider: String,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        / 86_400
+}
+
+/// Users are metered by wallet, whether they sent their key or were let in
+/// by node; everyone else by node
+pub fn caller_id(source: &Address, api_key: Option<&str>, state: &State) -> String {
+    api_key
+        .and_then(|k| {
+            state
+                .in_keys
+                .values()
+                .find(|u| auth::keys_match(k, &u.api_key))
+        })
+        .or_else(|| {
+            state
+                .in_keys
+                .values()
+                .find(|u| u.node.as_deref() == Some(source.node()))
+        })
+        .map(|u| u.wallet.clone())
+        .unwrap_or(source.node().to_string())
+}
+
+/// Daily limit: a terminal override wins over the definition
+pub fn daily_limit(state: &State, provider_name: &str) -> Option<u64> {
+    match state.quotas.get(provider_name) {
+        Some(limit) => *limit,
+        None => state
+            .definitions
+            .get(provider_name)
+            .and_then(|def| def.daily_limit),
+    }
+}
+
+/// Rows matching the optional caller and provider
+pub fn get(
+    db: &Sqlite,
+    caller: Option<&str>,
+    provider_name: Option<&str>,
+) -> anyhow::Result<Vec<UsageRow>> {
+    dbm::get_usage(db, caller, provider_name)?
+        .into_iter()
+        .map(|row| Ok(serde_json::from_value(serde_json::to_value(row)?)?))
+        .collect()
+}
+
+/// Runs before the upstream call, so callers over quota cost us nothing
+pub fn check_quota(
+    db: &Sqlite,
+    state: &State,
+    caller: &str,
+    provider_name: &str,
+) -> Result<(), ProviderError> {
+    let exceeded = |m: String| ProviderError::new(ProviderErrorCode::QuotaExceeded, m);
+    if let Some(user) = state.in_keys.get(caller) {
+        if !user.has_credit(provider_name) {
+            return Err(exceeded(format!("no credits left for {}", provider_name)));
+        }
+    }
+    if let Some(limit) = daily_limit(state, provider_name) {
+        let usage = get(db, Some(caller), Some(provider_name))?;
+        let calls = usage.first().map_or(0, |row| row.usage.calls_on(today()));
+        if calls >= limit {
+            return Err(exceeded(format!(
+                "daily limit of {} calls to {} reached",
+                limit, provider_name
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Counts a finished call; calls served upstream use up a credit
+pub fn record(db: &Sqlite, state: &mut State, caller: &str, provider_name: &str, outcome: Outcome) {
+    let res = get(db, Some(caller), Some(provider_name)).and_then(|rows| {
+        let mut usage = rows.into_iter().next().map(|r| r.usage).unwrap_or_default();
+        usage.add(&outcome, today());
+        dbm::put_usage(db, caller, provider_name, &usage)
+    });
+    if let Err(e) = res {
+        error!("couldn't count a call: {:?}", e);
+    }
+    if !outcome.uses_credit() {
+        return;
+    }
+    if let Some(user) = state.in_keys.get_mut(caller) {
+        if user.has_credit(provider_name) && user.credits.is_some() {
+            user.spend_credit(provider_name);
+            state.save();
+        }
+    }
+}
+
+/// Moves counters kept in state by older versions into the db
+pub fn migrate(db: &Sqlite, state: &mut State) -> anyhow::Result<()> {
+    if state.legacy_usage.is_empty() {
+        return Ok(());
+    }
+    for (caller, providers) in &state.legacy_usage {
+        for (provider_name, usage) in providers {
+            dbm::put_usage(db, caller, provider_name, usage)?;
+        }
+    }
+    state.legacy_usage.clear();
+    state.save();
+    Ok(())
+}
+
+/// caller -> provider name -> counters
+pub fn nested(rows: Vec<UsageRow>) -> HashMap<String, HashMap<String, Usage>> {
+    let mut nested = HashMap::<String, HashMap<String, Usage>>::new();
+    for row in rows {
+        nested
+            .entry(row.caller)
+            .or_default()
+            .insert(row.provider, row.usage);
+    }
+    nested
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_hits_are_counted_but_free() {
+        let mut usage = Usage::default();
+        usage.add(&Outcome::Served(10), 1);
+        usage.add(&Outcome::Cached(10), 1);
+        usage.add(&Outcome::Cached(10), 1);
+        assert_eq!(usage.calls, 3);
+        assert_eq!(usage.bytes, 30);
+        assert_eq!(usage.cache_hits, 2);
+        assert_eq!(usage.calls_on(1), 1);
+        assert!(Outcome::Served(10).uses_credit());
+        assert!(!Outcome::Cached(10).uses_credit());
+        assert!(!Outcome::Failed.uses_credit());
+    }
+
+    #[test]
+    fn failures_count_towards_the_day_but_not_bytes() {
+        let mut usage = Usage::default();
+        usage.add(&Outcome::Failed, 1);
+        assert_eq!(usage.errors, 1);
+        assert_eq!(usage.bytes, 0);
+        assert_eq!(usage.calls_on(1), 1);
+    }
+
+    #[test]
+    fn day_calls_start_over_each_day() {
+        let mut usage = Usage::default();
+        usage.add(&Outcome::Served(1), 1);
+        usage.add(&Outcome::Served(1), 1);
+        assert_eq!(usage.calls_on(1), 2);
+        assert_eq!(usage.calls_on(2), 0);
+        usage.add(&Outcome::Served(1), 2);
+        assert_eq!(usage.calls_on(2), 1);
+        assert_eq!(usage.calls, 3);
+    }
+
+    #[test]
+    fn rows_read_back_from_the_db_shape() {
+        let row = serde_json::json!({
+            "caller": "0xabc", "provider": "weatherapi", "calls": 2, "bytes": 40,
+            "errors": 0, "cache_hits": 1, "day": 20000, "day_calls": 1
+        });
+        let row = serde_json::from_value::<UsageRow>(row).unwrap();
+        assert_eq!(row.usage.cache_hits, 1);
+        assert_eq!(row.usage.calls_on(20000), 1);
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+
+// audit helpers both processes use, included by each with #[path]:
+// they're separate crates and kit builds each on its own.
+
+/// A fresh per-node key for arguments_digest
+pub fn new_audit_key() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+/// The arguments as JSON with sorted keys, so the same call always reads the same
+pub fn normalized_arguments(arguments: &HashMap<String, Value>) -> String {
+    // serde_json's Map keeps keys sorted, at every depth
+    serde_json::to_value(arguments)
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// hex HMAC-SHA256 of the normalized arguments, keyed with this node's
+/// audit_key so likely arguments can't be hashed and matched without it
+pub fn arguments_digest(key: &str, arguments: &HashMap<String, Value>) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("hmac takes keys of any length");
+    mac.update(normalized_arguments(arguments).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Terminal `key=value` words; a word without `=` belongs to the value before it,
+/// so `provider_name=Finnhub API` works
+pub fn params_from_words<'a>(words: impl Iterator<Item = &'a str>) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut last: Option<String> = None;
+    for word in words {
+        match (word.split_once('='), &last) {
+            (Some((key, value)), _) => {
+                params.insert(key.to_string(), value.to_string());
+                last = Some(key.to_string());
+            }
+            (None, Some(key)) => {
+                if let Some(value) = params.get_mut(key) {
+                    value.push(' ');
+                    value.push_str(word);
+                }
+            }
+            (None, None) => (),
+        }
+    }
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn digests_depend_on_the_key_not_the_key_order() {
+        let a = HashMap::from([
+            ("city".to_string(), json!("Lisbon")),
+            ("days".to_string(), json!(3)),
+        ]);
+        let b = HashMap::from([
+            ("days".to_string(), json!(3)),
+            ("city".to_string(), json!("Lisbon")),
+        ]);
+        assert_eq!(arguments_digest("k1", &a), arguments_digest("k1", &b));
+        assert_ne!(arguments_digest("k1", &a), arguments_digest("k2", &a));
+        assert_eq!(arguments_digest("k1", &a).len(), 64);
+    }
+
+    #[test]
+    fn words_without_a_key_extend_the_value_before() {
+        let params = params_from_words("provider_name=Finnhub API status=ok stray".split(' '));
+        assert_eq!(params["provider_name"], "Finnhub API");
+        assert_eq!(params["status"], "ok stray");
+        assert!(params_from_words("stray provider_name=x".split(' ')).len() == 1);
+    }
+}
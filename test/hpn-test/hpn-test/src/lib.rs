@@ -1,18 +1,94 @@
-use crate::hyperware::process::hpn::{HpnMessage, Request as HpnRequest, Response as HpnResponse, SendRequest};
-use crate::hyperware::process::tester::{Request as TesterRequest, Response as TesterResponse, RunRequest, FailResponse};
+use crate::hyperware::process::tester::{
+    FailResponse, Request as TesterRequest, Response as TesterResponse, RunRequest,
+};
 
-use hyperware_process_lib::{await_message, call_init, print_to_terminal, println, Address, ProcessId, Request, Response};
+use hyperware_process_lib::eth::{EthAction, EthResponse};
+use hyperware_process_lib::{
+    await_message, call_init, print_to_terminal, println, Address, ProcessId, Request, Response,
+};
+use serde_json::{json, Value};
 
 mod tester_lib;
 
 wit_bindgen::generate!({
     path: "target/wit",
-    world: "hpn-test-template-dot-os-v0",
+    world: "hpn-test-sortugdev-dot-os-v0",
     generate_unused_types: true,
     additional_derives: [PartialEq, serde::Deserialize, serde::Serialize, process_macros::SerdeJsonInto],
 });
 
-fn handle_message (our: &Address) -> anyhow::Result<()> {
+/// the fakechain anvil runs behind fakechain_router
+const CHAIN_ID: u64 = 31337;
+/// anvil's first two default accounts, both unlocked
+const PAYER: &str = "0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266";
+const WALLET: &str = "0x70997970C51812dc3A010C7d01b50e0d17dc79C8";
+/// a shipped definition, which takes no payments
+const PROVIDER_NAME: &str = "weatherapi";
+
+fn eth_rpc(method: &str, params: Value) -> anyhow::Result<Value> {
+    let action = EthAction::Request {
+        chain_id: CHAIN_ID,
+        method: method.to_string(),
+        params,
+    };
+    let res = Request::to(("our", "eth", "distro", "sys"))
+        .body(serde_json::to_vec(&action)?)
+        .send_and_await_response(15)??;
+    match serde_json::from_slice::<EthResponse>(res.body())? {
+        EthResponse::Response(value) => Ok(value),
+        EthResponse::Err(e) => Err(anyhow::anyhow!("{} failed: {:?}", method, e)),
+        _ => Err(anyhow::anyhow!("unexpected response to {}", method)),
+    }
+}
+
+/// same message the provider checks the claim's signature against
+fn claim_message(provider_name: &str, tx_hash: &str, node: &str) -> String {
+    format!(
+        "hpn payment {} for {} from {}",
+        tx_hash.to_lowercase(),
+        provider_name,
+        node
+    )
+}
+
+/// Pays WALLET from PAYER on the fakechain and signs a claim for `node`
+fn pay_and_sign(node: &str) -> anyhow::Result<Value> {
+    let tx_hash = eth_rpc(
+        "eth_sendTransaction",
+        json!([{ "from": PAYER, "to": WALLET, "value": "0x2386f26fc10000" }]),
+    )?;
+    let tx_hash = tx_hash
+        .as_str()
+        .ok_or(anyhow::anyhow!("no tx hash"))?
+        .to_string();
+    // anvil mines on every tx
+    let receipt = eth_rpc("eth_getTransactionReceipt", json!([tx_hash]))?;
+    if receipt["status"].as_str() != Some("0x1") {
+        return Err(anyhow::anyhow!("payment failed: {}", receipt));
+    }
+    let message = claim_message(PROVIDER_NAME, &tx_hash, node);
+    let signature = eth_rpc(
+        "personal_sign",
+        json!([format!("0x{}", hex::encode(message)), PAYER]),
+    )?;
+    Ok(json!({
+        "provider_name": PROVIDER_NAME,
+        "tx_hash": tx_hash,
+        "node": node,
+        "signature": signature,
+    }))
+}
+
+/// Sends a Pay claim and returns the error code it was refused with, if any
+fn claim(provider: &Address, claim: Value) -> anyhow::Result<Option<String>> {
+    let res = Request::to(provider.clone())
+        .body(serde_json::to_vec(&json!({ "Pay": claim }))?)
+        .send_and_await_response(15)??;
+    let body: Value = serde_json::from_slice(res.body())?;
+    Ok(body["code"].as_str().map(str::to_string))
+}
+
+fn handle_message(our: &Address) -> anyhow::Result<()> {
     let message = await_message().unwrap();
 
     if !message.is_request() {
@@ -40,45 +116,28 @@ fn handle_message (our: &Address) -> anyhow::Result<()> {
         return Ok(());
     }
 
-    // we are master node
+    // we are master node, buying from the provider on the second node
 
-    let our_hpn_address = Address {
-        node: our.node.clone(),
-        process: ProcessId::new(Some("hpn"), "hpn", "template.os"),
-    };
-    let their_hpn_address = Address {
+    let their_provider = Address {
         node: node_names[1].clone(),
-        process: ProcessId::new(Some("hpn"), "hpn", "template.os"),
+        process: ProcessId::new(Some("provider"), "hpn", "sortugdev.os"),
     };
 
-    // Send
+    // a claim binding the provider's own node, as if we'd seen it on its way
     print_to_terminal(0, "hpn_test: b");
-    let message: String = "hello".into();
-    let _ = Request::new()
-        .target(our_hpn_address.clone())
-        .body(HpnRequest::Send(SendRequest {
-            target: node_names[1].clone(),
-            message: message.clone(),
-        }))
-        .send_and_await_response(15)?.unwrap();
-
-    // Get history from receiver & test
-    print_to_terminal(0, "hpn_test: c");
-    let response = Request::new()
-        .target(their_hpn_address.clone())
-        .body(HpnRequest::History(our.node.clone()))
-        .send_and_await_response(15)?.unwrap();
-    if response.is_request() { fail!("hpn_test"); };
-    let HpnResponse::History(messages) = response.body().try_into()? else {
+    let stolen = pay_and_sign(&node_names[1])?;
+    let code = claim(&their_provider, stolen)?;
+    if code.as_deref() != Some("Forbidden") {
+        println!("claim for another node was answered with {code:?}");
         fail!("hpn_test");
-    };
-    let expected_messages = vec![HpnMessage {
-        author: our.node.clone(),
-        content: message,
-    }];
+    }
 
-    if messages != expected_messages {
-        println!("{messages:?} != {expected_messages:?}");
+    // our own claim gets past the node check, to the provider's terms
+    print_to_terminal(0, "hpn_test: c");
+    let ours = pay_and_sign(&our.node)?;
+    let code = claim(&their_provider, ours)?;
+    if code.as_deref() != Some("NotFound") {
+        println!("claim for {PROVIDER_NAME} was answered with {code:?}");
         fail!("hpn_test");
     }
 
@@ -96,12 +155,12 @@ fn init(our: Address) {
 
     loop {
         match handle_message(&our) {
-            Ok(()) => {},
+            Ok(()) => {}
             Err(e) => {
                 print_to_terminal(0, format!("hpn_test: error: {e:?}").as_str());
 
                 fail!("hpn_test");
-            },
+            }
         };
     }
 }
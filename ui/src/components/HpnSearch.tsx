@@ -7,6 +7,10 @@ const Main: React.FC = () => {
   const [searchResults, setSearchResults] = useState<ProviderJson[]>([]);
   const [searchError, setSearchError] = useState<string | null>(null);
   const [input, setInput] = useState("");
+  // the search the results are for, pages after the first ask for it again
+  const [query, setQuery] = useState("");
+  const [nextCursor, setNextCursor] = useState<string | undefined>();
+  const [loadingMore, setLoadingMore] = useState(false);
   // const [data, setData] = useState<AllProviders>({});
   const [data, setData] = useState<Provider[]>([]);
 
@@ -49,18 +53,33 @@ const Main: React.FC = () => {
     e.preventDefault();
     setSearchResults([]);
     setSearchError("");
+    setNextCursor(undefined);
     const inp = input.toLowerCase();
+    setQuery(inp);
     setLoading(true);
     const res = await searchDB(inp);
-    console.log({ res });
-    if ("error" in res) setSearchError("error searching index");
+    if ("error" in res) setSearchError(`error searching index: ${res.error}`);
     else {
-      setSearchResults(res.ok);
-      if (res.ok.length === 0) setSearchError("No providers found");
+      setSearchResults(res.ok.items);
+      setNextCursor(res.ok.next_cursor);
+      if (res.ok.items.length === 0) setSearchError("No providers found");
     }
     setLoading(false);
   }
 
+  async function loadMore() {
+    if (!nextCursor) return;
+    setLoadingMore(true);
+    const res = await searchDB(query, nextCursor);
+    if ("error" in res) setSearchError(`error loading more: ${res.error}`);
+    else {
+      const items = res.ok.items;
+      setSearchResults((s) => [...s, ...items]);
+      setNextCursor(res.ok.next_cursor);
+    }
+    setLoadingMore(false);
+  }
+
   return (
     <div className="explorer-container">
       <div className="search-container">
@@ -85,7 +104,20 @@ const Main: React.FC = () => {
       {loading ? (
         <p>Searching...</p>
       ) : (
-        searchResults.length > 0 && <SearchResults results={searchResults} />
+        searchResults.length > 0 && (
+          <>
+            <SearchResults results={searchResults} />
+            {nextCursor && (
+              <button
+                onClick={loadMore}
+                disabled={loadingMore}
+                className="search-button"
+              >
+                {loadingMore ? "Loading..." : "More"}
+              </button>
+            )}
+          </>
+        )
       )}
     </div>
   );
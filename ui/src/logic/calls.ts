@@ -1,6 +1,7 @@
 import type {
   AllProviders,
   AsyncRes,
+  Page,
   Provider,
   ProviderJson,
   Result,
@@ -20,16 +21,39 @@ export async function fetchState(): AsyncRes<AllProviders> {
   }
 }
 
-export async function fetchAll(): AsyncRes<Provider[]> {
+/** providers asked for per page */
+export const PAGE_LIMIT = 50;
+
+/** Fetches one page of a listing route, with the indexer's error message when it fails */
+async function fetchPage<T>(
+  path: string,
+  params: Record<string, string>,
+  cursor?: string,
+): AsyncRes<Page<T>> {
   try {
-    const response = await fetch(API_PATH + "/all");
-    const j = await response.json();
+    const query = new URLSearchParams({
+      ...params,
+      limit: `${PAGE_LIMIT}`,
+      ...(cursor ? { cursor } : {}),
+    });
+    const response = await fetch(API_PATH + path + "?" + query);
+    const j = await response.json().catch(() => null);
+    if (!response.ok) {
+      const message = j && j.message ? j.message : response.statusText;
+      return { error: `${response.status}: ${message}` };
+    }
+    if (!j || !Array.isArray(j.items))
+      return { error: "unexpected answer from the indexer" };
     return { ok: j };
   } catch (e) {
     return { error: `${e}` };
   }
 }
 
+export async function fetchAll(cursor?: string): AsyncRes<Page<Provider>> {
+  return fetchPage<Provider>("/all", {}, cursor);
+}
+
 function parseFromState(j: any): AllProviders {
   let data: AllProviders = {};
   for (let hash in j) {
@@ -53,23 +77,17 @@ function parseFromState(j: any): AllProviders {
   return data;
 }
 
-export async function fetchCategory(cat: string): AsyncRes<Provider[]> {
-  try {
-    const response = await fetch(API_PATH + "/cat?cat=" + cat);
-    const j = await response.json();
-    return { ok: j };
-  } catch (e) {
-    return { error: `${e}` };
-  }
+export async function fetchCategory(
+  cat: string,
+  cursor?: string,
+): AsyncRes<Page<Provider>> {
+  return fetchPage<Provider>("/cat", { cat }, cursor);
 }
-export async function searchDB(query: string): AsyncRes<ProviderJson[]> {
-  try {
-    const response = await fetch(API_PATH + "/search?q=" + query);
-    const j = await response.json();
-    return { ok: j };
-  } catch (e) {
-    return { error: `${e}` };
-  }
+export async function searchDB(
+  query: string,
+  cursor?: string,
+): AsyncRes<Page<ProviderJson>> {
+  return fetchPage<ProviderJson>("/search", { q: query }, cursor);
 }
 
 function parseSqlite(ps: ProviderJson[]): Provider[] {
@@ -1,5 +1,6 @@
 export type AsyncRes<T> = Promise<Result<T>>;
 export type Result<T> = { ok: T } | { error: string };
+export type Page<T> = { items: T[]; next_cursor?: string };
 
 export type AllProviders = Record<Category, Array<Provider>>;
 export type Category = string;